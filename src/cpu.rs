@@ -0,0 +1,464 @@
+use std::{collections::HashMap, fmt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    X,
+    Y,
+}
+
+impl Reg {
+    const ALL: [Reg; 2] = [Reg::X, Reg::Y];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Reg::X => "x",
+            Reg::Y => "y",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Reg> {
+        Reg::ALL.into_iter().find(|reg| reg.name() == name)
+    }
+}
+
+/// Jump offsets are relative to the jumping instruction, like `jmp -1` for a tight loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Noop,
+    Addx(isize),
+    Addy(isize),
+    Jmp(isize),
+    /// Jumps if the register is zero.
+    Jz(Reg, isize),
+    /// Jumps if the register is not zero.
+    Jnz(Reg, isize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register,
+    Value,
+    /// A jump offset; assembly source may give a label instead.
+    Offset,
+}
+
+#[derive(Debug)]
+pub struct OpInfo {
+    pub name: &'static str,
+    pub cycles: usize,
+    pub operands: &'static [Operand],
+}
+
+/// Indexed by opcode.
+pub const INSTRUCTION_SET: [OpInfo; 6] = [
+    OpInfo {
+        name: "noop",
+        cycles: 1,
+        operands: &[],
+    },
+    OpInfo {
+        name: "addx",
+        cycles: 2,
+        operands: &[Operand::Value],
+    },
+    OpInfo {
+        name: "addy",
+        cycles: 2,
+        operands: &[Operand::Value],
+    },
+    OpInfo {
+        name: "jmp",
+        cycles: 1,
+        operands: &[Operand::Offset],
+    },
+    OpInfo {
+        name: "jz",
+        cycles: 2,
+        operands: &[Operand::Register, Operand::Offset],
+    },
+    OpInfo {
+        name: "jnz",
+        cycles: 2,
+        operands: &[Operand::Register, Operand::Offset],
+    },
+];
+
+impl Instruction {
+    pub fn opcode(&self) -> usize {
+        match self {
+            Instruction::Noop => 0,
+            Instruction::Addx(_) => 1,
+            Instruction::Addy(_) => 2,
+            Instruction::Jmp(_) => 3,
+            Instruction::Jz(..) => 4,
+            Instruction::Jnz(..) => 5,
+        }
+    }
+
+    pub fn info(&self) -> &'static OpInfo {
+        &INSTRUCTION_SET[self.opcode()]
+    }
+
+    /// Operands in the order of `info().operands`, registers by their index in `Reg::ALL`.
+    fn operands(&self) -> Vec<isize> {
+        match *self {
+            Instruction::Noop => vec![],
+            Instruction::Addx(v) | Instruction::Addy(v) | Instruction::Jmp(v) => vec![v],
+            Instruction::Jz(reg, offset) | Instruction::Jnz(reg, offset) => {
+                vec![reg as isize, offset]
+            }
+        }
+    }
+
+    fn from_operands(opcode: usize, operands: &[isize]) -> Instruction {
+        let reg = || Reg::ALL[operands[0] as usize];
+        match opcode {
+            0 => Instruction::Noop,
+            1 => Instruction::Addx(operands[0]),
+            2 => Instruction::Addy(operands[0]),
+            3 => Instruction::Jmp(operands[0]),
+            4 => Instruction::Jz(reg(), operands[1]),
+            5 => Instruction::Jnz(reg(), operands[1]),
+            _ => panic!("no opcode {}", opcode),
+        }
+    }
+
+    fn offset(&self) -> Option<isize> {
+        match *self {
+            Instruction::Jmp(offset) | Instruction::Jz(_, offset) | Instruction::Jnz(_, offset) => {
+                Some(offset)
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the instruction, with `target` in place of the jump offset if given.
+    fn write(&self, f: &mut impl fmt::Write, target: Option<&str>) -> fmt::Result {
+        let info = self.info();
+        write!(f, "{}", info.name)?;
+        for (kind, value) in info.operands.iter().zip(self.operands()) {
+            match (kind, target) {
+                (Operand::Register, _) => write!(f, " {}", Reg::ALL[value as usize].name())?,
+                (Operand::Offset, Some(label)) => write!(f, " {}", label)?,
+                _ => write!(f, " {}", value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Puzzle format.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, None)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AsmError {
+    UnknownOpcode { line: usize, name: String },
+    BadOperands { line: usize, text: String },
+    UnknownLabel { line: usize, label: String },
+    DuplicateLabel { line: usize, label: String },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::UnknownOpcode { line, name } => {
+                write!(f, "line {}: unknown opcode {:?}", line, name)
+            }
+            AsmError::BadOperands { line, text } => {
+                write!(f, "line {}: bad operands in {:?}", line, text)
+            }
+            AsmError::UnknownLabel { line, label } => {
+                write!(f, "line {}: unknown label {:?}", line, label)
+            }
+            AsmError::DuplicateLabel { line, label } => {
+                write!(f, "line {}: label {:?} defined twice", line, label)
+            }
+        }
+    }
+}
+
+/// Assembles source with one instruction per line. Lines may also be `label:` definitions,
+/// blank, or `#` comments, and jump offsets may be given as labels. The puzzle input format is
+/// valid source.
+pub fn assemble(source: &str) -> Result<Vec<Instruction>, AsmError> {
+    let mut labels = HashMap::new();
+    // (line number, opcode, operands, label to resolve into the offset)
+    let mut parsed = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Some(label) = trimmed.strip_suffix(':') {
+            if labels.insert(label.to_string(), parsed.len()).is_some() {
+                return Err(AsmError::DuplicateLabel {
+                    line: line_no,
+                    label: label.to_string(),
+                });
+            }
+            continue;
+        }
+
+        let bad_operands = || AsmError::BadOperands {
+            line: line_no,
+            text: line.to_string(),
+        };
+        let mut items = trimmed.split_whitespace();
+        let name = items.next().unwrap();
+        let opcode = INSTRUCTION_SET
+            .iter()
+            .position(|info| info.name == name)
+            .ok_or_else(|| AsmError::UnknownOpcode {
+                line: line_no,
+                name: name.to_string(),
+            })?;
+        let items: Vec<&str> = items.collect();
+        let kinds = INSTRUCTION_SET[opcode].operands;
+        if items.len() != kinds.len() {
+            return Err(bad_operands());
+        }
+        let mut operands = Vec::new();
+        let mut label = None;
+        for (kind, item) in kinds.iter().zip(items) {
+            let value = match (kind, item.parse::<isize>()) {
+                (Operand::Register, _) => Reg::parse(item).ok_or_else(bad_operands)? as isize,
+                (_, Ok(value)) => value,
+                (Operand::Offset, Err(_)) => {
+                    label = Some(item.to_string());
+                    0
+                }
+                (Operand::Value, Err(_)) => return Err(bad_operands()),
+            };
+            operands.push(value);
+        }
+        parsed.push((line_no, opcode, operands, label));
+    }
+
+    parsed
+        .into_iter()
+        .enumerate()
+        .map(|(index, (line, opcode, mut operands, label))| {
+            if let Some(label) = label {
+                let target = labels
+                    .get(&label)
+                    .ok_or(AsmError::UnknownLabel { line, label })?;
+                *operands.last_mut().unwrap() = *target as isize - index as isize;
+            }
+            Ok(Instruction::from_operands(opcode, &operands))
+        })
+        .collect()
+}
+
+/// The program in puzzle format, one instruction per line.
+pub fn to_puzzle_format(program: &[Instruction]) -> String {
+    program.iter().map(|inst| format!("{}\n", inst)).collect()
+}
+
+/// Turns a program back into assembly source, with labels `l0`, `l1`, ... for every jump
+/// target inside the program. Jumps that leave the program keep their numeric offset.
+pub fn disassemble(program: &[Instruction]) -> String {
+    let target = |index: usize, inst: &Instruction| {
+        let target = index as isize + inst.offset()?;
+        (0..=program.len() as isize)
+            .contains(&target)
+            .then_some(target as usize)
+    };
+    let mut targets: Vec<usize> = program
+        .iter()
+        .enumerate()
+        .filter_map(|(index, inst)| target(index, inst))
+        .collect();
+    targets.sort();
+    targets.dedup();
+    let label = |index: usize| {
+        let i = targets.binary_search(&index).ok()?;
+        Some(format!("l{}", i))
+    };
+
+    let mut source = String::new();
+    for index in 0..=program.len() {
+        if let Some(label) = label(index) {
+            source += &format!("{}:\n", label);
+        }
+        if let Some(inst) = program.get(index) {
+            let target = target(index, inst).and_then(label);
+            source += "    ";
+            inst.write(&mut source, target.as_deref()).unwrap();
+            source += "\n";
+        }
+    }
+    source
+}
+
+/// Something that gets to look at the CPU during every cycle.
+pub trait Hook {
+    fn on_cycle(&mut self, cpu: &Cpu);
+}
+
+impl<F: FnMut(&Cpu)> Hook for F {
+    fn on_cycle(&mut self, cpu: &Cpu) {
+        self(cpu)
+    }
+}
+
+/// Runs a program the way the handheld does: an instruction takes its effect at the end of its
+/// last cycle, so hooks see the registers as they were before it. The CPU halts when the
+/// program counter leaves the program.
+#[derive(Debug)]
+pub struct Cpu {
+    program: Vec<Instruction>,
+    /// Number of the current cycle, starting at 1.
+    pub cycle: usize,
+    /// Index of the current instruction.
+    pub pc: isize,
+    /// Cycles of the current instruction that have already passed.
+    pub elapsed: usize,
+    regs: [isize; 2],
+}
+
+impl Cpu {
+    pub fn new(program: Vec<Instruction>) -> Cpu {
+        Cpu {
+            program,
+            cycle: 1,
+            pc: 0,
+            elapsed: 0,
+            regs: [1, 0],
+        }
+    }
+
+    pub fn reg(&self, reg: Reg) -> isize {
+        self.regs[reg as usize]
+    }
+
+    pub fn x(&self) -> isize {
+        self.reg(Reg::X)
+    }
+
+    pub fn current(&self) -> Option<Instruction> {
+        usize::try_from(self.pc)
+            .ok()
+            .and_then(|pc| self.program.get(pc))
+            .copied()
+    }
+
+    /// Runs one cycle, or returns false if the CPU has halted.
+    pub fn step_cycle(&mut self, hooks: &mut [&mut dyn Hook]) -> bool {
+        let Some(inst) = self.current() else {
+            return false;
+        };
+        for hook in hooks.iter_mut() {
+            hook.on_cycle(self);
+        }
+        self.cycle += 1;
+        self.elapsed += 1;
+        if self.elapsed == inst.info().cycles {
+            self.elapsed = 0;
+            self.execute(inst);
+        }
+        true
+    }
+
+    /// Runs until the CPU halts, which some programs never do.
+    pub fn run(&mut self, hooks: &mut [&mut dyn Hook]) {
+        while self.step_cycle(hooks) {}
+    }
+
+    fn execute(&mut self, inst: Instruction) {
+        let jump = match inst {
+            Instruction::Noop => None,
+            Instruction::Addx(v) => {
+                self.regs[Reg::X as usize] += v;
+                None
+            }
+            Instruction::Addy(v) => {
+                self.regs[Reg::Y as usize] += v;
+                None
+            }
+            Instruction::Jmp(offset) => Some(offset),
+            Instruction::Jz(reg, offset) => (self.reg(reg) == 0).then_some(offset),
+            Instruction::Jnz(reg, offset) => (self.reg(reg) != 0).then_some(offset),
+        };
+        self.pc += jump.unwrap_or(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNTDOWN: &str = "\
+# adds 3 to x, y times
+    addy 4
+loop:
+    jz y done
+    addx 3
+    addy -1
+    jmp loop
+done:
+    noop
+";
+
+    #[test]
+    fn test_assemble() {
+        let program = assemble(COUNTDOWN).unwrap();
+        assert_eq!(
+            to_puzzle_format(&program),
+            "addy 4\njz y 4\naddx 3\naddy -1\njmp -3\nnoop\n"
+        );
+        assert_eq!(assemble(&to_puzzle_format(&program)).unwrap(), program);
+        assert_eq!(
+            disassemble(&program),
+            "    addy 4\nl0:\n    jz y l1\n    addx 3\n    addy -1\n    jmp l0\nl1:\n    noop\n"
+        );
+        assert_eq!(assemble(&disassemble(&program)).unwrap(), program);
+
+        assert_eq!(
+            assemble("noop\nfoo 1"),
+            Err(AsmError::UnknownOpcode {
+                line: 2,
+                name: "foo".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("jz z 1"),
+            Err(AsmError::BadOperands {
+                line: 1,
+                text: "jz z 1".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("jmp nowhere"),
+            Err(AsmError::UnknownLabel {
+                line: 1,
+                label: "nowhere".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("a:\na:"),
+            Err(AsmError::DuplicateLabel {
+                line: 2,
+                label: "a".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let mut cpu = Cpu::new(assemble(COUNTDOWN).unwrap());
+        let mut seen = Vec::new();
+        let mut record = |cpu: &Cpu| seen.push((cpu.cycle, cpu.pc, cpu.x()));
+        cpu.run(&mut [&mut record]);
+        assert_eq!((cpu.x(), cpu.reg(Reg::Y), cpu.pc), (13, 0, 6));
+        // addy, then 4 rounds of jz, addx, addy and jmp, then jz and noop.
+        assert_eq!(cpu.cycle - 1, 2 + 4 * 7 + 2 + 1);
+        assert_eq!(seen.len(), cpu.cycle - 1);
+        assert_eq!(seen[2..5], [(3, 1, 1), (4, 1, 1), (5, 2, 1)]);
+        assert!(!cpu.step_cycle(&mut []));
+    }
+}
@@ -1,82 +1,556 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+};
+
+use cpu::{Cpu, Hook, Reg};
+
+mod cpu;
 mod lib;
 
+/// Adds up the signal strength at cycles `first`, `first + every`, ...
 #[derive(Debug)]
-enum Instruction {
-    Addx(isize),
-    Noop,
+struct SignalSampler {
+    first: usize,
+    every: usize,
+    sum: isize,
 }
 
-impl Instruction {
-    fn parse(line: &str) -> Instruction {
-        match (line, line.split_once(" ")) {
-            ("noop", None) => Instruction::Noop,
-            (_, Some(("addx", val))) => Instruction::Addx(val.parse::<isize>().unwrap()),
-            _ => panic!("unexpected input {}", line),
+impl Hook for SignalSampler {
+    fn on_cycle(&mut self, cpu: &Cpu) {
+        if cpu.cycle >= self.first && (cpu.cycle - self.first).is_multiple_of(self.every) {
+            self.sum += cpu.cycle as isize * cpu.x();
         }
     }
 }
 
+/// Glyph width and height of the letters the CRT shows. Letters are one column apart.
+const GLYPH_WIDTH: usize = 4;
+const GLYPH_HEIGHT: usize = 6;
+
+/// The letters of the puzzle font, rows top to bottom.
+const FONT: [(char, &str); 18] = [
+    ('A', ".##.#..##..######..##..#"),
+    ('B', "###.#..####.#..##..####."),
+    ('C', ".##.#..##...#...#..#.##."),
+    ('E', "#####...###.#...#...####"),
+    ('F', "#####...###.#...#...#..."),
+    ('G', ".##.#..##...#.###..#.###"),
+    ('H', "#..##..######..##..##..#"),
+    ('I', ".###..#...#...#...#..###"),
+    ('J', "..##...#...#...##..#.##."),
+    ('K', "#..##.#.##..#.#.#.#.#..#"),
+    ('L', "#...#...#...#...#...####"),
+    ('O', ".##.#..##..##..##..#.##."),
+    ('P', "###.#..##..####.#...#..."),
+    ('R', "###.#..##..####.#.#.#..#"),
+    ('S', ".####...#....##....####."),
+    ('U', "#..##..##..##..##..#.##."),
+    // Y is a column wider than the rest, its arms reach into the gap.
+    ('Y', "#...#....#.#..#...#...#."),
+    ('Z', "####...#..#..#..#...####"),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum OcrError {
+    /// The frame is this many pixels tall instead of one line of text.
+    Height(usize),
+    /// Indices of the glyphs that are not letters, from the left.
+    Unrecognized(Vec<usize>),
+}
+
+impl fmt::Display for OcrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OcrError::Height(height) => write!(
+                f,
+                "frame is {} pixels tall, letters are {}",
+                height, GLYPH_HEIGHT
+            ),
+            OcrError::Unrecognized(glyphs) => {
+                write!(f, "unrecognized glyphs at positions {:?}", glyphs)
+            }
+        }
+    }
+}
+
+/// Draws one pixel per cycle, lit where the 3 pixel wide sprite at `x` is, and keeps the frame.
 #[derive(Debug)]
-struct Cpu {
-    cycle: usize,
-    x: isize,
-    signal_strength_sum: isize,
-    scan_line: [char; 40],
-}
-
-impl Cpu {
-    fn new() -> Cpu {
-        Cpu {
-            cycle: 1,
-            x: 1,
-            signal_strength_sum: 0,
-            scan_line: ['.'; 40],
-        }
-    }
-    fn maybe_record_signal_strength(&mut self) {
-        if (self.cycle + 20) % 40 == 0 {
-            let signal_strength = self.cycle as isize * self.x;
-            // dbg!(self.cycle, signal_strength);
-            self.signal_strength_sum += signal_strength;
-        }
-    }
-    fn handle_cycle(&mut self) {
-        self.maybe_record_signal_strength();
-        let pixel_pos = (self.cycle - 1) % 40;
-        self.scan_line[pixel_pos] = if (pixel_pos as isize - self.x).abs() <= 1 {
-            '#'
+struct Crt {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+    /// Number of cycles drawn so far.
+    drawn: usize,
+}
+
+impl Crt {
+    fn new(width: usize, height: usize) -> Crt {
+        Crt {
+            width,
+            height,
+            pixels: vec![false; width * height],
+            drawn: 0,
+        }
+    }
+
+    fn lit(&self, row: usize, col: usize) -> bool {
+        self.pixels[row * self.width + col]
+    }
+
+    /// Reads the frame as letters of the puzzle font.
+    fn read_letters(&self) -> Result<String, OcrError> {
+        if self.height != GLYPH_HEIGHT {
+            return Err(OcrError::Height(self.height));
+        }
+        let glyphs = (self.width + 1) / (GLYPH_WIDTH + 1);
+        let mut letters = String::new();
+        let mut unrecognized = Vec::new();
+        for i in 0..glyphs {
+            let left = i * (GLYPH_WIDTH + 1);
+            let glyph: String = (0..GLYPH_HEIGHT)
+                .flat_map(|row| (left..left + GLYPH_WIDTH).map(move |col| (row, col)))
+                .map(|(row, col)| if self.lit(row, col) { '#' } else { '.' })
+                .collect();
+            match FONT.iter().find(|(_, pattern)| *pattern == glyph) {
+                Some((letter, _)) => letters.push(*letter),
+                None => unrecognized.push(i),
+            }
+        }
+        if unrecognized.is_empty() {
+            Ok(letters)
         } else {
-            '.'
-        };
-        if pixel_pos == 39 {
-            let line = self.scan_line.iter().collect::<String>();
-            dbg!(line);
+            Err(OcrError::Unrecognized(unrecognized))
+        }
+    }
+}
+
+impl Hook for Crt {
+    fn on_cycle(&mut self, cpu: &Cpu) {
+        let pixel = (cpu.cycle - 1) % self.pixels.len();
+        let col = (pixel % self.width) as isize;
+        self.pixels[pixel] = (col - cpu.x()).abs() <= 1;
+        self.drawn += 1;
+    }
+}
+
+impl Crt {
+    /// The frame being drawn, with pixels the beam has not reached yet left blank.
+    fn partial_view(&self) -> String {
+        let mut view = String::new();
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let pixel = row * self.width + col;
+                view.push(match self.lit(row, col) {
+                    _ if pixel >= self.drawn => ' ',
+                    true => '#',
+                    false => '.',
+                });
+            }
+            view.push('\n');
         }
+        view
     }
-    fn execute(&mut self, inst: Instruction) {
-        // dbg!(&self, &inst);
-        self.handle_cycle();
+}
 
-        match inst {
-            Instruction::Addx(v) => {
-                self.cycle += 1;
-                self.handle_cycle();
-                self.x += v;
+impl fmt::Display for Crt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in 0..self.height {
+            for col in 0..self.width {
+                write!(f, "{}", if self.lit(row, col) { '#' } else { '.' })?;
             }
-            Instruction::Noop => {}
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Breakpoint {
+    /// Stops before the cycle runs.
+    Cycle(usize),
+    /// Stops before the instruction at this index starts.
+    Instruction(usize),
+    /// Stops when the register changes to this value.
+    Register(Reg, isize),
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::Cycle(cycle) => write!(f, "cycle {}", cycle),
+            Breakpoint::Instruction(pc) => write!(f, "pc {}", pc),
+            Breakpoint::Register(reg, value) => write!(f, "{} {}", reg.name(), value),
+        }
+    }
+}
+
+/// A register, optionally compared with a constant: `x`, `x > 3`, `y == 0`.
+#[derive(Debug, PartialEq, Eq)]
+struct Watch {
+    reg: Reg,
+    compare: Option<(String, isize)>,
+}
+
+impl Watch {
+    fn parse(text: &str) -> Option<Watch> {
+        let items: Vec<&str> = text.split_whitespace().collect();
+        let reg = Reg::parse(items.first()?)?;
+        let compare = match items[1..] {
+            [] => None,
+            [op @ ("==" | "!=" | "<" | "<=" | ">" | ">="), value] => {
+                Some((op.to_string(), value.parse().ok()?))
+            }
+            _ => return None,
+        };
+        Some(Watch { reg, compare })
+    }
+
+    fn eval(&self, cpu: &Cpu) -> String {
+        let value = cpu.reg(self.reg);
+        let Some((op, rhs)) = &self.compare else {
+            return value.to_string();
+        };
+        let holds = match op.as_str() {
+            "==" => value == *rhs,
+            "!=" => value != *rhs,
+            "<" => value < *rhs,
+            "<=" => value <= *rhs,
+            ">" => value > *rhs,
+            _ => value >= *rhs,
+        };
+        holds.to_string()
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reg.name())?;
+        if let Some((op, rhs)) = &self.compare {
+            write!(f, " {} {}", op, rhs)?;
         }
-        self.cycle += 1;
+        Ok(())
     }
 }
 
-fn parse_input<'a>(input: &'a str) -> impl Iterator<Item = Instruction> + 'a {
-    input.lines().map(|line| Instruction::parse(line.trim()))
+const DEBUGGER_HELP: &str = "\
+s [N]        step N cycles
+n [N]        step N instructions
+c            continue until a breakpoint, a watch changes or the CPU halts
+b cycle N    break before cycle N
+b pc N       break before instruction N
+b x V        break when x becomes V (also y)
+d N          delete breakpoint N
+w EXPR       watch x or y, optionally compared: w x > 3
+crt          show the frame drawn so far
+i            show registers, breakpoints and watches
+q            quit
+";
+
+/// Gives up on `c` after this many cycles, in case the program loops forever.
+const CONTINUE_LIMIT: usize = 1_000_000;
+
+/// Runs a program on the CRT under user control. Every command returns the text to show.
+struct Debugger {
+    cpu: Cpu,
+    crt: Crt,
+    breakpoints: Vec<Breakpoint>,
+    /// Watches with their value at the last stop.
+    watches: Vec<(Watch, String)>,
+}
+
+impl Debugger {
+    fn new(program: Vec<cpu::Instruction>) -> Debugger {
+        Debugger {
+            cpu: Cpu::new(program),
+            crt: Crt::new(40, 6),
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+        }
+    }
+
+    fn status(&self) -> String {
+        let inst = match self.cpu.current() {
+            Some(inst) => format!("{} ({}/{})", inst, self.cpu.elapsed + 1, inst.info().cycles),
+            None => "halted".to_string(),
+        };
+        format!(
+            "cycle {} pc {}: {}, x={} y={}\n",
+            self.cpu.cycle,
+            self.cpu.pc,
+            inst,
+            self.cpu.x(),
+            self.cpu.reg(Reg::Y)
+        )
+    }
+
+    /// Runs one cycle, and says why execution should stop there, if it should.
+    fn step(&mut self) -> Option<String> {
+        let before = [self.cpu.x(), self.cpu.reg(Reg::Y)];
+        if !self.cpu.step_cycle(&mut [&mut self.crt]) {
+            return Some("halted\n".to_string());
+        }
+        let mut reasons = String::new();
+        for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+            let hit = match *breakpoint {
+                Breakpoint::Cycle(cycle) => self.cpu.cycle == cycle,
+                Breakpoint::Instruction(pc) => self.cpu.pc == pc as isize && self.cpu.elapsed == 0,
+                Breakpoint::Register(reg, value) => {
+                    self.cpu.reg(reg) == value && before[reg as usize] != value
+                }
+            };
+            if hit {
+                reasons += &format!("breakpoint {}: {}\n", i, breakpoint);
+            }
+        }
+        for (watch, last) in self.watches.iter_mut() {
+            let value = watch.eval(&self.cpu);
+            if value != *last {
+                reasons += &format!("watch {}: {} -> {}\n", watch, last, value);
+                *last = value;
+            }
+        }
+        (!reasons.is_empty()).then_some(reasons)
+    }
+
+    fn command(&mut self, line: &str) -> String {
+        let line = line.trim();
+        let items: Vec<&str> = line.split_whitespace().collect();
+        let count = || match items.get(1) {
+            Some(n) => n.parse::<usize>().ok(),
+            None => Some(1),
+        };
+        let mut out = String::new();
+        match items[..] {
+            ["s", ..] | ["n", ..] => {
+                let Some(count) = count() else {
+                    return format!("bad count in {:?}\n", line);
+                };
+                let mut stop = None;
+                for _ in 0..count {
+                    stop = self.step();
+                    // `n` finishes the instruction; `jmp 0` never leaves its own.
+                    while stop.is_none() && items[0] == "n" && self.cpu.elapsed > 0 {
+                        stop = self.step();
+                    }
+                    if stop.is_some() {
+                        break;
+                    }
+                }
+                out += &stop.unwrap_or_default();
+                out += &self.status();
+            }
+            ["c"] => {
+                match (0..CONTINUE_LIMIT).find_map(|_| self.step()) {
+                    Some(reason) => out += &reason,
+                    None => out += &format!("still running after {} cycles\n", CONTINUE_LIMIT),
+                }
+                out += &self.status();
+            }
+            ["b", kind, value] => {
+                let breakpoint = match (kind, value.parse::<isize>()) {
+                    // Breakpoints are checked after stepping into a cycle, so the current one
+                    // and those before it would never be hit.
+                    ("cycle", Ok(cycle)) if cycle > 0 && cycle as usize <= self.cpu.cycle => {
+                        return format!("cycle {} has already started\n", cycle)
+                    }
+                    ("cycle", Ok(cycle)) if cycle > 0 => Breakpoint::Cycle(cycle as usize),
+                    ("pc", Ok(pc)) if pc >= 0 => Breakpoint::Instruction(pc as usize),
+                    (reg, Ok(value)) if Reg::parse(reg).is_some() => {
+                        Breakpoint::Register(Reg::parse(reg).unwrap(), value)
+                    }
+                    _ => return format!("bad breakpoint {:?}\n", line),
+                };
+                out += &format!("breakpoint {}: {}\n", self.breakpoints.len(), breakpoint);
+                self.breakpoints.push(breakpoint);
+            }
+            ["d", n] => match n.parse::<usize>() {
+                Ok(n) if n < self.breakpoints.len() => {
+                    out += &format!("deleted breakpoint {}\n", self.breakpoints.remove(n));
+                }
+                _ => return format!("no breakpoint {}\n", n),
+            },
+            ["w", ..] => {
+                let Some(watch) = Watch::parse(&line[1..]) else {
+                    return format!("bad watch {:?}\n", line);
+                };
+                let value = watch.eval(&self.cpu);
+                out += &format!("watch {}: {}\n", watch, value);
+                self.watches.push((watch, value));
+            }
+            ["crt"] => out += &self.crt.partial_view(),
+            ["i"] => {
+                out += &self.status();
+                for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+                    out += &format!("breakpoint {}: {}\n", i, breakpoint);
+                }
+                for (watch, value) in self.watches.iter() {
+                    out += &format!("watch {}: {}\n", watch, value);
+                }
+            }
+            _ => out += DEBUGGER_HELP,
+        }
+        out
+    }
+
+    /// Reads commands from stdin until `q` or the end of input.
+    fn interact(&mut self) {
+        print!("{}", self.status());
+        let stdin = io::stdin();
+        loop {
+            print!("(dbg) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap() == 0 || line.trim() == "q" {
+                break;
+            }
+            print!("{}", self.command(&line));
+        }
+    }
 }
 
 fn main() {
     let input = lib::read_input();
-    let cmd_iter = parse_input(&input);
-    let mut cpu = Cpu::new();
-    cmd_iter.for_each(|inst| cpu.execute(inst));
-    dbg!(&cpu.signal_strength_sum);
+    let program = cpu::assemble(&input).unwrap_or_else(|e| panic!("{}", e));
+    match std::env::args().nth(2).as_deref() {
+        Some("asm") => print!("{}", cpu::to_puzzle_format(&program)),
+        Some("disasm") => print!("{}", cpu::disassemble(&program)),
+        Some("debug") => Debugger::new(program).interact(),
+        _ => {
+            let mut signal = SignalSampler {
+                first: 20,
+                every: 40,
+                sum: 0,
+            };
+            let mut crt = Crt::new(40, 6);
+            Cpu::new(program).run(&mut [&mut signal, &mut crt]);
+            dbg!(&signal.sum);
+            print!("{}", crt);
+            // The samples don't draw letters, so report what couldn't be read and carry on.
+            match crt.read_letters() {
+                Ok(letters) => {
+                    dbg!(letters);
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal_strength() {
+        let program = cpu::assemble("noop\naddx 3\naddx -5").unwrap();
+        let mut signal = SignalSampler {
+            first: 2,
+            every: 2,
+            sum: 0,
+        };
+        let mut cpu = Cpu::new(program);
+        cpu.run(&mut [&mut signal]);
+        assert_eq!(signal.sum, 2 + 4 * 4);
+        assert_eq!((cpu.cycle, cpu.x()), (6, -1));
+
+        let mut crt = Crt::new(3, 2);
+        let mut cpu = Cpu::new(cpu::assemble("noop\naddx 3\naddx -5").unwrap());
+        cpu.run(&mut [&mut crt]);
+        assert_eq!(crt.to_string(), "###\n...\n");
+    }
+
+    #[test]
+    fn test_read_letters() {
+        let mut crt = Crt::new(14, 6);
+        let frame = "\
+.##..###..####
+#..#.#..#.#...
+#..#.###..###.
+####.#..#.#...
+#..#.#..#.#...
+#..#.###..####
+";
+        for (pixel, c) in frame.lines().flat_map(|l| l.chars()).enumerate() {
+            crt.pixels[pixel] = c == '#';
+        }
+        assert_eq!(crt.to_string(), frame);
+        assert_eq!(crt.read_letters(), Ok("ABE".to_string()));
+
+        crt.pixels[0] = true;
+        crt.pixels[2 * 14 + 13] = true;
+        assert_eq!(crt.read_letters(), Err(OcrError::Unrecognized(vec![0, 2])));
+
+        let mut crt = Crt::new(9, 6);
+        let frame = "\
+#...#.###
+#...#..#.
+.#.#...#.
+..#....#.
+..#....#.
+..#...###
+";
+        for (pixel, c) in frame.lines().flat_map(|l| l.chars()).enumerate() {
+            crt.pixels[pixel] = c == '#';
+        }
+        assert_eq!(crt.read_letters(), Ok("YI".to_string()));
+
+        assert_eq!(Crt::new(9, 5).read_letters(), Err(OcrError::Height(5)));
+    }
+
+    #[test]
+    fn test_debugger() {
+        let program = cpu::assemble("noop\naddx 3\naddx -5\njmp 0").unwrap();
+        let mut debugger = Debugger::new(program);
+        let mut session = String::new();
+        for command in [
+            "b cycle 1",
+            "b pc 2",
+            "w x > 3",
+            "c",
+            "n",
+            "crt",
+            "s 2",
+            "b x -1",
+            "c",
+            "d 5",
+        ] {
+            session += &format!("> {}\n{}", command, debugger.command(command));
+        }
+        let blank = format!("{}\n", " ".repeat(40));
+        let crt = format!("#####{}{}", &blank[5..], blank.repeat(5));
+        assert_eq!(
+            session,
+            format!(
+                "\
+> b cycle 1
+cycle 1 has already started
+> b pc 2
+breakpoint 0: pc 2
+> w x > 3
+watch x > 3: false
+> c
+breakpoint 0: pc 2
+watch x > 3: false -> true
+cycle 4 pc 2: addx -5 (1/2), x=4 y=0
+> n
+watch x > 3: true -> false
+cycle 6 pc 3: jmp 0 (1/1), x=-1 y=0
+> crt
+{}> s 2
+cycle 8 pc 3: jmp 0 (1/1), x=-1 y=0
+> b x -1
+breakpoint 1: x -1
+> c
+still running after {} cycles
+cycle {} pc 3: jmp 0 (1/1), x=-1 y=0
+> d 5
+no breakpoint 5
+",
+                crt,
+                CONTINUE_LIMIT,
+                CONTINUE_LIMIT + 8,
+            )
+        );
+    }
 }
@@ -1,13 +1,267 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
 
 use itertools::{self, Itertools};
 
-type MonkeyFn = Box<dyn Fn(usize) -> usize>;
+mod lib;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinOp {
+    fn symbol(&self) -> char {
+        match self {
+            BinOp::Add => '+',
+            BinOp::Sub => '-',
+            BinOp::Mul => '*',
+            BinOp::Div => '/',
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            BinOp::Add | BinOp::Sub => 1,
+            BinOp::Mul | BinOp::Div => 2,
+        }
+    }
+}
+
+/// How a monkey's worry about an item changes: `new = <expr>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Old,
+    Const(usize),
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ExprError {
+    /// Byte offset into the expression text.
+    pos: usize,
+    message: &'static str,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.pos + 1, self.message)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum EvalError {
+    Overflow {
+        expr: String,
+        lhs: usize,
+        rhs: usize,
+    },
+    DivisionByZero {
+        expr: String,
+    },
+    /// Division does not carry over to remainders.
+    DivisionUnderModulus {
+        expr: String,
+    },
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::Overflow { expr, lhs, rhs } => {
+                write!(f, "{} overflows for operands {} and {}", expr, lhs, rhs)
+            }
+            EvalError::DivisionByZero { expr } => write!(f, "{} divides by zero", expr),
+            EvalError::DivisionUnderModulus { expr } => {
+                write!(f, "{} can't be evaluated under a modulus", expr)
+            }
+        }
+    }
+}
+
+/// Recursive descent over `expr := term (('+' | '-') term)*`, `term := atom (('*' | '/') atom)*`
+/// and `atom := 'old' | number | '(' expr ')'`.
+struct ExprParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn error(&self, message: &'static str) -> ExprError {
+        ExprError {
+            pos: self.pos,
+            message,
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+        self.text[self.pos..].chars().next()
+    }
+
+    fn binary(
+        &mut self,
+        level: u8,
+        operand: fn(&mut Self) -> Result<Expr, ExprError>,
+    ) -> Result<Expr, ExprError> {
+        let mut lhs = operand(self)?;
+        loop {
+            let op = match self.peek() {
+                Some('+') => BinOp::Add,
+                Some('-') => BinOp::Sub,
+                Some('*') => BinOp::Mul,
+                Some('/') => BinOp::Div,
+                _ => return Ok(lhs),
+            };
+            if op.precedence() != level {
+                return Ok(lhs);
+            }
+            self.pos += 1;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(operand(self)?));
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ExprError> {
+        self.binary(1, Self::term)
+    }
+
+    fn term(&mut self) -> Result<Expr, ExprError> {
+        self.binary(2, Self::atom)
+    }
+
+    fn atom(&mut self) -> Result<Expr, ExprError> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.expr()?;
+                if self.peek() != Some(')') {
+                    return Err(self.error("expected ')'"));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let rest = &self.text[self.pos..];
+                let len = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let value = rest[..len]
+                    .parse()
+                    .map_err(|_| self.error("number too large"))?;
+                self.pos += len;
+                Ok(Expr::Const(value))
+            }
+            _ if self.text[self.pos..].starts_with("old") => {
+                self.pos += 3;
+                Ok(Expr::Old)
+            }
+            _ => Err(self.error("expected 'old', a number or '('")),
+        }
+    }
+}
+
+impl Expr {
+    fn parse(text: &str) -> Result<Expr, ExprError> {
+        let mut parser = ExprParser { text, pos: 0 };
+        let expr = parser.expr()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(_) => Err(parser.error("unexpected character")),
+        }
+    }
+
+    /// Evaluates with checked arithmetic.
+    fn eval(&self, old: usize) -> Result<usize, EvalError> {
+        match self {
+            Expr::Old => Ok(old),
+            Expr::Const(value) => Ok(*value),
+            Expr::Binary(lhs, op, rhs) => {
+                let (a, b) = (lhs.eval(old)?, rhs.eval(old)?);
+                let result = match op {
+                    BinOp::Add => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Mul => a.checked_mul(b),
+                    BinOp::Div if b == 0 => {
+                        return Err(EvalError::DivisionByZero {
+                            expr: self.to_string(),
+                        })
+                    }
+                    BinOp::Div => Some(a / b),
+                };
+                result.ok_or_else(|| EvalError::Overflow {
+                    expr: self.to_string(),
+                    lhs: a,
+                    rhs: b,
+                })
+            }
+        }
+    }
+
+    /// Evaluates modulo `modulus`, which never overflows but can't divide.
+    fn eval_mod(&self, old: usize, modulus: usize) -> Result<usize, EvalError> {
+        let m = modulus as u128;
+        match self {
+            Expr::Old => Ok(old % modulus),
+            Expr::Const(value) => Ok(value % modulus),
+            Expr::Binary(lhs, op, rhs) => {
+                let a = lhs.eval_mod(old, modulus)? as u128;
+                let b = rhs.eval_mod(old, modulus)? as u128;
+                let result = match op {
+                    BinOp::Add => (a + b) % m,
+                    BinOp::Sub => (a + m - b) % m,
+                    BinOp::Mul => a * b % m,
+                    BinOp::Div => {
+                        return Err(EvalError::DivisionUnderModulus {
+                            expr: self.to_string(),
+                        })
+                    }
+                };
+                Ok(result as usize)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Old => write!(f, "old"),
+            Expr::Const(value) => write!(f, "{}", value),
+            Expr::Binary(lhs, op, rhs) => {
+                // Operators group to the left, so a right operand on the same level needs
+                // parentheses too.
+                let needs_parens = |expr: &Expr, right: bool| match expr {
+                    Expr::Binary(_, inner, _) => {
+                        inner.precedence() < op.precedence()
+                            || (right && inner.precedence() == op.precedence())
+                    }
+                    _ => false,
+                };
+                for (expr, right) in [(lhs, false), (rhs, true)] {
+                    if right {
+                        write!(f, " {} ", op.symbol())?;
+                    }
+                    if needs_parens(expr, right) {
+                        write!(f, "({})", expr)?;
+                    } else {
+                        write!(f, "{}", expr)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
 struct Monkey {
     items: VecDeque<usize>,
-    // new = fn(old)
-    operation: MonkeyFn,
+    operation: Expr,
     divisor: usize,
     monkey_if_true: usize,
     monkey_if_false: usize,
@@ -18,7 +272,7 @@ struct Monkey {
 impl Monkey {
     fn new(
         items: impl IntoIterator<Item = usize>,
-        operation: MonkeyFn,
+        operation: Expr,
         divisor: usize,
         monkey_if_true: usize,
         monkey_if_false: usize,
@@ -34,139 +288,248 @@ impl Monkey {
     }
 }
 
+/// Where an item is at the start of a round: (monkey, worry).
+type ItemState = (usize, usize);
+
+/// An item thrown during `round` (counted from 1), with its worry level before `from`
+/// inspected it and after, when it was thrown.
+#[derive(Debug, PartialEq, Eq)]
+struct Throw {
+    round: usize,
+    from: usize,
+    to: usize,
+    worry_before: usize,
+    worry_after: usize,
+}
+
+fn trace_csv(trace: &[Throw]) -> String {
+    let mut csv = String::from("round,from,to,worry_before,worry_after\n");
+    for t in trace {
+        csv += &format!(
+            "{},{},{},{},{}\n",
+            t.round, t.from, t.to, t.worry_before, t.worry_after
+        );
+    }
+    csv
+}
+
 struct State {
     monkeys: Vec<Monkey>,
     divisor_product: usize,
+    /// Number of rounds done.
+    round: usize,
+    /// Every throw so far, if tracing.
+    trace: Option<Vec<Throw>>,
 }
 
-impl State {
-    fn build_sample() -> State {
-        let monkeys = vec![
-            // Monkey 0:
-            //   Starting items: 79, 98
-            //   Operation: new = old * 19
-            //   Test: divisible by 23
-            //     If true: throw to monkey 2
-            //     If false: throw to monkey 3
-            Monkey::new([79, 98], Box::new(|old| old * 19), 23, 2, 3),
-            // Monkey 1:
-            //   Starting items: 54, 65, 75, 74
-            //   Operation: new = old + 6
-            //   Test: divisible by 19
-            //     If true: throw to monkey 2
-            //     If false: throw to monkey 0
-            Monkey::new([54, 65, 75, 74], Box::new(|old| old + 6), 19, 2, 0),
-            // Monkey 2:
-            //   Starting items: 79, 60, 97
-            //   Operation: new = old * old
-            //   Test: divisible by 13
-            //     If true: throw to monkey 1
-            //     If false: throw to monkey 3
-            Monkey::new([79, 60, 97], Box::new(|old| old * old), 13, 1, 3),
-            // Monkey 3:
-            //   Starting items: 74
-            //   Operation: new = old + 3
-            //   Test: divisible by 17
-            //     If true: throw to monkey 0
-            //     If false: throw to monkey 1
-            Monkey::new([74], Box::new(|old| old + 3), 17, 0, 1),
-        ];
-        let divisor_product = monkeys.iter().map(|m| m.divisor).product();
-        State {
-            monkeys,
-            divisor_product,
-        }
-    }
+#[derive(Debug, PartialEq, Eq)]
+enum ParseError {
+    /// The line is not the `expected` part of a monkey definition.
+    Malformed {
+        line: usize,
+        text: String,
+        expected: &'static str,
+    },
+    /// The input ends in the middle of a monkey.
+    Truncated {
+        expected: &'static str,
+    },
+    /// Monkeys must be numbered 0, 1, 2, ...
+    WrongNumber {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+    BadOperation {
+        line: usize,
+        error: ExprError,
+    },
+    NoSuchMonkey {
+        line: usize,
+        monkey: usize,
+    },
+    SelfThrow {
+        line: usize,
+        monkey: usize,
+    },
+    ZeroDivisor {
+        line: usize,
+    },
+    /// Worry levels are kept modulo the product of all divisors, which has to fit a `usize`.
+    DivisorOverflow,
+}
 
-    fn build_input() -> State {
-        let monkeys = vec![
-            // Monkey 0:
-            //   Starting items: 66, 71, 94
-            //   Operation: new = old * 5
-            //   Test: divisible by 3
-            //     If true: throw to monkey 7
-            //     If false: throw to monkey 4
-            Monkey::new([66, 71, 94], Box::new(|old| old * 5), 3, 7, 4),
-            // Monkey 1:
-            //   Starting items: 70
-            //   Operation: new = old + 6
-            //   Test: divisible by 17
-            //     If true: throw to monkey 3
-            //     If false: throw to monkey 0
-            Monkey::new([70], Box::new(|old| old + 6), 17, 3, 0),
-            // Monkey 2:
-            //   Starting items: 62, 68, 56, 65, 94, 78
-            //   Operation: new = old + 5
-            //   Test: divisible by 2
-            //     If true: throw to monkey 3
-            //     If false: throw to monkey 1
-            Monkey::new([62, 68, 56, 65, 94, 78], Box::new(|old| old + 5), 2, 3, 1),
-            // Monkey 3:
-            //   Starting items: 89, 94, 94, 67
-            //   Operation: new = old + 2
-            //   Test: divisible by 19
-            //     If true: throw to monkey 7
-            //     If false: throw to monkey 0
-            Monkey::new([89, 94, 94, 67], Box::new(|old| old + 2), 19, 7, 0),
-            // Monkey 4:
-            //   Starting items: 71, 61, 73, 65, 98, 98, 63
-            //   Operation: new = old * 7
-            //   Test: divisible by 11
-            //     If true: throw to monkey 5
-            //     If false: throw to monkey 6
-            Monkey::new(
-                [71, 61, 73, 65, 98, 98, 63],
-                Box::new(|old| old * 7),
-                11,
-                5,
-                6,
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Malformed {
+                line,
+                text,
+                expected,
+            } => write!(
+                f,
+                "line {}: expected {:?}, found {:?}",
+                line, expected, text
             ),
-            // Monkey 5:
-            //   Starting items: 55, 62, 68, 61, 60
-            //   Operation: new = old + 7
-            //   Test: divisible by 5
-            //     If true: throw to monkey 2
-            //     If false: throw to monkey 1
-            Monkey::new([55, 62, 68, 61, 60], Box::new(|old| old + 7), 5, 2, 1),
-            // Monkey 6:
-            //   Starting items: 93, 91, 69, 64, 72, 89, 50, 71
-            //   Operation: new = old + 1
-            //   Test: divisible by 13
-            //     If true: throw to monkey 5
-            //     If false: throw to monkey 2
-            Monkey::new(
-                [93, 91, 69, 64, 72, 89, 50, 71],
-                Box::new(|old| old + 1),
-                13,
-                5,
-                2,
+            ParseError::Truncated { expected } => {
+                write!(f, "unexpected end of input, expected {:?}", expected)
+            }
+            ParseError::WrongNumber {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "line {}: expected monkey {}, found {}",
+                line, expected, found
             ),
-            // Monkey 7:
-            //   Starting items: 76, 50
-            //   Operation: new = old * old
-            //   Test: divisible by 7
-            //     If true: throw to monkey 4
-            //     If false: throw to monkey 6
-            Monkey::new([76, 50], Box::new(|old| old * old), 7, 4, 6),
-        ];
-        let divisor_product = monkeys.iter().map(|m| m.divisor).product();
-        State {
+            ParseError::BadOperation { line, error } => {
+                write!(f, "line {}: bad operation: {}", line, error)
+            }
+            ParseError::NoSuchMonkey { line, monkey } => {
+                write!(f, "line {}: there is no monkey {}", line, monkey)
+            }
+            ParseError::SelfThrow { line, monkey } => {
+                write!(f, "line {}: monkey {} throws to itself", line, monkey)
+            }
+            ParseError::ZeroDivisor { line } => {
+                write!(f, "line {}: can't test divisibility by 0", line)
+            }
+            ParseError::DivisorOverflow => write!(f, "the product of the divisors overflows"),
+        }
+    }
+}
+
+/// Reads monkey definitions one line at a time, skipping blank lines.
+struct FieldReader<'a> {
+    lines: Vec<&'a str>,
+    /// Index of the next line.
+    next: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    fn at_end(&mut self) -> bool {
+        while self.next < self.lines.len() && self.lines[self.next].trim().is_empty() {
+            self.next += 1;
+        }
+        self.next == self.lines.len()
+    }
+
+    fn malformed(&self, line: usize, expected: &'static str) -> ParseError {
+        ParseError::Malformed {
+            line,
+            text: self.lines[line - 1].to_string(),
+            expected,
+        }
+    }
+
+    /// The line number and the rest of the next line, which must start with `expected`.
+    fn field(&mut self, expected: &'static str) -> Result<(usize, &'a str), ParseError> {
+        if self.at_end() {
+            return Err(ParseError::Truncated { expected });
+        }
+        self.next += 1;
+        let value = self.lines[self.next - 1]
+            .trim()
+            .strip_prefix(expected)
+            .ok_or_else(|| self.malformed(self.next, expected))?;
+        Ok((self.next, value.trim()))
+    }
+
+    fn number(&mut self, expected: &'static str) -> Result<(usize, usize), ParseError> {
+        let (line, value) = self.field(expected)?;
+        let number = value.trim_end_matches(':').parse::<usize>();
+        number
+            .map(|n| (line, n))
+            .map_err(|_| self.malformed(line, expected))
+    }
+}
+
+/// Parses the puzzle's monkey definitions, which are separated by blank lines.
+fn parse_monkeys(text: &str) -> Result<Vec<Monkey>, ParseError> {
+    let mut reader = FieldReader {
+        lines: text.lines().collect(),
+        next: 0,
+    };
+    let mut monkeys = Vec::new();
+    // Line numbers of the throws, to check them once all monkeys are known.
+    let mut throws = Vec::new();
+    while !reader.at_end() {
+        let (line, id) = reader.number("Monkey")?;
+        if id != monkeys.len() {
+            return Err(ParseError::WrongNumber {
+                line,
+                expected: monkeys.len(),
+                found: id,
+            });
+        }
+        let items = match reader.field("Starting items:")? {
+            (_, "") => Vec::new(),
+            (line, items) => items
+                .split(',')
+                .map(|item| item.trim().parse::<usize>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| reader.malformed(line, "Starting items:"))?,
+        };
+        let (line, operation) = reader.field("Operation: new =")?;
+        let operation =
+            Expr::parse(operation).map_err(|error| ParseError::BadOperation { line, error })?;
+        let (line, divisor) = reader.number("Test: divisible by")?;
+        if divisor == 0 {
+            return Err(ParseError::ZeroDivisor { line });
+        }
+        let if_true = reader.number("If true: throw to monkey")?;
+        let if_false = reader.number("If false: throw to monkey")?;
+        throws.extend([(id, if_true), (id, if_false)]);
+        monkeys.push(Monkey::new(
+            items, operation, divisor, if_true.1, if_false.1,
+        ));
+    }
+
+    for (id, (line, monkey)) in throws {
+        if monkey >= monkeys.len() {
+            return Err(ParseError::NoSuchMonkey { line, monkey });
+        }
+        if monkey == id {
+            return Err(ParseError::SelfThrow { line, monkey });
+        }
+    }
+    Ok(monkeys)
+}
+
+impl State {
+    fn new(monkeys: Vec<Monkey>) -> Result<State, ParseError> {
+        let divisor_product = monkeys
+            .iter()
+            .try_fold(1usize, |product, m| product.checked_mul(m.divisor))
+            .ok_or(ParseError::DivisorOverflow)?;
+        Ok(State {
             monkeys,
             divisor_product,
-        }
+            round: 0,
+            trace: None,
+        })
+    }
+
+    fn parse(text: &str) -> Result<State, ParseError> {
+        parse_monkeys(text).and_then(State::new)
     }
 
     fn do_round(&mut self, do_div_by_three: bool) {
+        self.round += 1;
         for i in 0..self.monkeys.len() {
             while let Some(mut item) = self.monkeys[i].items.pop_front() {
+                let worry_before = item;
                 let (new_item, to_monkey) = {
                     let monkey = &mut self.monkeys[i];
                     monkey.inspect_count += 1;
                     // Inspect.
-                    item = (monkey.operation)(item);
-                    if do_div_by_three {
-                        item /= 3;
-                    }
+                    let inspected = if do_div_by_three {
+                        monkey.operation.eval(item).map(|item| item / 3)
+                    } else {
+                        monkey.operation.eval_mod(item, self.divisor_product)
+                    };
+                    item = inspected.unwrap_or_else(|e| panic!("monkey {}: {}", i, e));
                     // GCD would be more efficient, but I was lazy.
                     item %= self.divisor_product;
                     let to_monkey = if item % monkey.divisor == 0 {
@@ -177,16 +540,115 @@ impl State {
 
                     (item, to_monkey)
                 };
+                if let Some(trace) = self.trace.as_mut() {
+                    trace.push(Throw {
+                        round: self.round,
+                        from: i,
+                        to: to_monkey,
+                        worry_before,
+                        worry_after: new_item,
+                    });
+                }
                 self.monkeys[to_monkey].items.push_back(new_item);
             }
         }
     }
+
+    /// What each monkey holds, as the puzzle shows it.
+    fn holding_report(&self) -> String {
+        let mut report = format!(
+            "After round {}, the monkeys are holding items with these worry levels:\n",
+            self.round
+        );
+        for (i, monkey) in self.monkeys.iter().enumerate() {
+            report += &format!("Monkey {}: {}\n", i, monkey.items.iter().join(", "));
+        }
+        report
+    }
+
+    /// How busy each monkey has been, as the puzzle shows it.
+    fn inspection_report(&self) -> String {
+        let mut report = format!("== After round {} ==\n", self.round);
+        for (i, monkey) in self.monkeys.iter().enumerate() {
+            report += &format!(
+                "Monkey {} inspected items {} times.\n",
+                i, monkey.inspect_count
+            );
+        }
+        report
+    }
+
+    /// Takes an item through one round without relief, counting the monkeys that inspect it.
+    /// It keeps going while it is thrown to monkeys that have yet to take their turn.
+    fn item_round(&self, (mut monkey, mut worry): ItemState, inspected: &mut [u64]) -> ItemState {
+        loop {
+            let m = &self.monkeys[monkey];
+            inspected[monkey] += 1;
+            worry = m
+                .operation
+                .eval_mod(worry, self.divisor_product)
+                .unwrap_or_else(|e| panic!("monkey {}: {}", monkey, e));
+            let to_monkey = if worry % m.divisor == 0 {
+                m.monkey_if_true
+            } else {
+                m.monkey_if_false
+            };
+            if to_monkey <= monkey {
+                return (to_monkey, worry);
+            }
+            monkey = to_monkey;
+        }
+    }
+
+    /// Inspection counts per monkey after `rounds` rounds without relief.
+    ///
+    /// Items don't affect each other, and each one only has finitely many states modulo
+    /// `divisor_product`, so every item's trajectory ends up in a cycle. Following each item
+    /// until its state repeats takes as long as the cycle, however many rounds there are.
+    fn inspect_counts(&self, rounds: u64) -> Vec<u64> {
+        let n = self.monkeys.len();
+        let mut total = vec![0; n];
+        for (monkey, m) in self.monkeys.iter().enumerate() {
+            for item in m.items.iter() {
+                let mut seen: HashMap<ItemState, usize> = HashMap::new();
+                // Inspections so far, after each round.
+                let mut history = vec![vec![0; n]];
+                let mut state = (monkey, item % self.divisor_product);
+                let counts = loop {
+                    let round = history.len() - 1;
+                    if round as u64 == rounds {
+                        break history[round].clone();
+                    }
+                    if let Some(&start) = seen.get(&state) {
+                        let period = (round - start) as u64;
+                        let cycles = (rounds - start as u64) / period;
+                        let rest = ((rounds - start as u64) % period) as usize;
+                        break (0..n)
+                            .map(|i| {
+                                let per_cycle = history[round][i] - history[start][i];
+                                history[start + rest][i] + cycles * per_cycle
+                            })
+                            .collect();
+                    }
+                    seen.insert(state, round);
+                    let mut counts = history[round].clone();
+                    state = self.item_round(state, &mut counts);
+                    history.push(counts);
+                };
+                for (total, count) in total.iter_mut().zip(counts) {
+                    *total += count;
+                }
+            }
+        }
+        total
+    }
 }
 
 fn main() {
+    let input = lib::read_input();
     {
         // Part 1:
-        let mut state = State::build_input();
+        let mut state = State::parse(&input).unwrap_or_else(|e| panic!("{}", e));
         for _ in 0..20 {
             state.do_round(true);
         }
@@ -202,7 +664,7 @@ fn main() {
     }
     // Part 2:
     {
-        let mut state = State::build_input();
+        let mut state = State::parse(&input).unwrap_or_else(|e| panic!("{}", e));
         for _ in 0..10000 {
             state.do_round(false);
         }
@@ -216,4 +678,295 @@ fn main() {
             .product();
         dbg!(monkey_business);
     }
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let flag = |name: &str| args.iter().any(|arg| arg == name);
+    let option = |name: &str| {
+        args.iter()
+            .find_map(|arg| arg.strip_prefix(&format!("{}=", name)))
+    };
+    if flag("trace") {
+        // Traces the puzzle's part 1 with `relief`, and part 2 otherwise.
+        let relief = flag("relief");
+        let default_rounds = if relief { 20 } else { 10000 };
+        let rounds = option("rounds").map_or(default_rounds, |r| r.parse().expect("bad rounds"));
+        let reports: Vec<usize> = option("report").map_or(vec![rounds], |r| {
+            r.split(',')
+                .map(|n| n.parse().expect("bad report"))
+                .collect()
+        });
+        let mut state = State::parse(&input).unwrap_or_else(|e| panic!("{}", e));
+        state.trace = Some(Vec::new());
+        for _ in 0..rounds {
+            state.do_round(relief);
+            if reports.contains(&state.round) {
+                println!("{}", state.holding_report());
+                println!("{}", state.inspection_report());
+            }
+        }
+        let trace = state.trace.unwrap();
+        match option("csv") {
+            Some(path) => std::fs::write(path, trace_csv(&trace)).expect("write failed"),
+            None => println!("{} throws", trace.len()),
+        }
+    } else if let Some(rounds) = option("rounds") {
+        let rounds = rounds.parse::<u64>().expect("bad round count");
+        let state = State::parse(&input).unwrap_or_else(|e| panic!("{}", e));
+        let monkey_business: u128 = state
+            .inspect_counts(rounds)
+            .into_iter()
+            .sorted()
+            .rev()
+            .take(2)
+            .map(u128::from)
+            .product();
+        dbg!(monkey_business);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+Monkey 0:
+  Starting items: 79, 98
+  Operation: new = old * 19
+  Test: divisible by 23
+    If true: throw to monkey 2
+    If false: throw to monkey 3
+
+Monkey 1:
+  Starting items: 54, 65, 75, 74
+  Operation: new = old + 6
+  Test: divisible by 19
+    If true: throw to monkey 2
+    If false: throw to monkey 0
+
+Monkey 2:
+  Starting items: 79, 60, 97
+  Operation: new = old * old
+  Test: divisible by 13
+    If true: throw to monkey 1
+    If false: throw to monkey 3
+
+Monkey 3:
+  Starting items: 74
+  Operation: new = old + 3
+  Test: divisible by 17
+    If true: throw to monkey 0
+    If false: throw to monkey 1
+";
+
+    #[test]
+    fn test_parse() {
+        let mut state = State::parse(SAMPLE).unwrap();
+        assert_eq!(state.divisor_product, 23 * 19 * 13 * 17);
+        assert_eq!(state.monkeys[1].items, [54, 65, 75, 74]);
+        assert_eq!(state.monkeys[2].operation.eval(3), Ok(9));
+        assert_eq!(
+            (
+                state.monkeys[3].monkey_if_true,
+                state.monkeys[3].monkey_if_false
+            ),
+            (0, 1)
+        );
+        for _ in 0..20 {
+            state.do_round(true);
+        }
+        let counts: Vec<usize> = state.monkeys.iter().map(|m| m.inspect_count).collect();
+        assert_eq!(counts, [101, 95, 7, 105]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| State::parse(text).err();
+        assert_eq!(
+            error(&SAMPLE.replace("old + 6", "old ^ 6")),
+            Some(ParseError::BadOperation {
+                line: 10,
+                error: ExprError {
+                    pos: 4,
+                    message: "unexpected character"
+                }
+            })
+        );
+        assert_eq!(
+            error(&SAMPLE.replace("new = old + 6", "old + 6")),
+            Some(ParseError::Malformed {
+                line: 10,
+                text: "  Operation: old + 6".to_string(),
+                expected: "Operation: new ="
+            })
+        );
+        assert_eq!(
+            error(&SAMPLE.replace("Monkey 2:", "Monkey 5:")),
+            Some(ParseError::WrongNumber {
+                line: 15,
+                expected: 2,
+                found: 5
+            })
+        );
+        assert_eq!(
+            error(&SAMPLE.replace("true: throw to monkey 1", "true: throw to monkey 4")),
+            Some(ParseError::NoSuchMonkey {
+                line: 19,
+                monkey: 4
+            })
+        );
+        assert_eq!(
+            error(&SAMPLE.replace("false: throw to monkey 0", "false: throw to monkey 1")),
+            Some(ParseError::SelfThrow {
+                line: 13,
+                monkey: 1
+            })
+        );
+        assert_eq!(
+            error(&SAMPLE.replace("divisible by 19", "divisible by 0")),
+            Some(ParseError::ZeroDivisor { line: 11 })
+        );
+        assert_eq!(
+            error(&SAMPLE.replace("divisible by 19", "divisible by 18446744073709551557")),
+            Some(ParseError::DivisorOverflow)
+        );
+        assert_eq!(
+            error(&SAMPLE[..SAMPLE.len() - 30]),
+            Some(ParseError::Truncated {
+                expected: "If false: throw to monkey"
+            })
+        );
+    }
+
+    #[test]
+    fn test_expr() {
+        let expr = Expr::parse("(old + 2) * old - 10 / (3 - 1)").unwrap();
+        assert_eq!(expr.eval(4), Ok(19));
+        assert_eq!(expr.to_string(), "(old + 2) * old - 10 / (3 - 1)");
+        for text in ["old - (old - 1)", "old - old - 1", "old / (2 * 3)", "(old)"] {
+            let expr = Expr::parse(text).unwrap();
+            assert_eq!(Expr::parse(&expr.to_string()), Ok(expr));
+        }
+        assert_eq!(Expr::parse("old - (old - 1)").unwrap().eval(7), Ok(1));
+        assert_eq!(Expr::parse("old - old - 1").unwrap().eval(7).ok(), None);
+        assert_eq!(Expr::parse("(old)").unwrap().to_string(), "old");
+
+        assert_eq!(
+            Expr::parse("old * (old + 1"),
+            Err(ExprError {
+                pos: 14,
+                message: "expected ')'"
+            })
+        );
+        assert_eq!(
+            Expr::parse("old *"),
+            Err(ExprError {
+                pos: 5,
+                message: "expected 'old', a number or '('"
+            })
+        );
+
+        let square = Expr::parse("old * old").unwrap();
+        assert_eq!(
+            square.eval(1 << 33),
+            Err(EvalError::Overflow {
+                expr: "old * old".to_string(),
+                lhs: 1 << 33,
+                rhs: 1 << 33
+            })
+        );
+        assert_eq!(
+            square.eval_mod(1 << 33, 1000),
+            Ok(((1u128 << 66) % 1000) as usize)
+        );
+        assert_eq!(Expr::parse("3 - old").unwrap().eval_mod(5, 7), Ok(5));
+        assert_eq!(
+            Expr::parse("old / 0").unwrap().eval(1),
+            Err(EvalError::DivisionByZero {
+                expr: "old / 0".to_string()
+            })
+        );
+        assert_eq!(
+            Expr::parse("old / 2").unwrap().eval_mod(4, 7),
+            Err(EvalError::DivisionUnderModulus {
+                expr: "old / 2".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_inspect_counts() {
+        for (rounds, expected) in [
+            (1, [2, 4, 3, 6]),
+            (20, [99, 97, 8, 103]),
+            (10000, [52166, 47830, 1938, 52013]),
+        ] {
+            let state = State::parse(SAMPLE).unwrap();
+            assert_eq!(state.inspect_counts(rounds), expected);
+        }
+
+        let initial = State::parse(SAMPLE).unwrap();
+        let mut state = State::parse(SAMPLE).unwrap();
+        for rounds in 1..=300 {
+            state.do_round(false);
+            let counts: Vec<u64> = state
+                .monkeys
+                .iter()
+                .map(|m| m.inspect_count as u64)
+                .collect();
+            assert_eq!(initial.inspect_counts(rounds), counts);
+        }
+
+        // Once every item cycles, counts grow in proportion to the number of rounds.
+        let counts = initial.inspect_counts(1_000_000_000_000);
+        for (count, per_10000) in counts.iter().zip([52166, 47830, 1938, 52013]) {
+            let expected = per_10000 * 100_000_000;
+            assert!(count.abs_diff(expected) < expected / 100, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut state = State::parse(SAMPLE).unwrap();
+        state.trace = Some(Vec::new());
+        state.do_round(true);
+        assert_eq!(
+            state.holding_report(),
+            "\
+After round 1, the monkeys are holding items with these worry levels:
+Monkey 0: 20, 23, 27, 26
+Monkey 1: 2080, 25, 167, 207, 401, 1046
+Monkey 2: \n\
+Monkey 3: \n"
+        );
+        let trace = state.trace.as_ref().unwrap();
+        assert_eq!(trace.len(), 2 + 4 + 3 + 5);
+        assert_eq!(
+            trace[0],
+            Throw {
+                round: 1,
+                from: 0,
+                to: 3,
+                worry_before: 79,
+                worry_after: 500
+            }
+        );
+        let csv = trace_csv(trace);
+        assert!(csv.starts_with("round,from,to,worry_before,worry_after\n1,0,3,79,500\n"));
+        assert_eq!(csv.lines().count(), 1 + trace.len());
+
+        let mut state = State::parse(SAMPLE).unwrap();
+        for _ in 0..20 {
+            state.do_round(false);
+        }
+        assert_eq!(
+            state.inspection_report(),
+            "\
+== After round 20 ==
+Monkey 0 inspected items 99 times.
+Monkey 1 inspected items 97 times.
+Monkey 2 inspected items 8 times.
+Monkey 3 inspected items 103 times.
+"
+        );
+        assert_eq!(state.trace, None);
+    }
 }
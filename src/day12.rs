@@ -1,10 +1,68 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
+};
 
 mod lib;
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
 struct Pos(isize, isize);
 
+/// How a hiker may move around the map, and what each step costs.
+#[derive(Debug, Clone)]
+struct Rules {
+    /// Highest climb allowed in one step.
+    max_ascent: isize,
+    /// Deepest drop allowed in one step, if there is a limit.
+    max_descent: Option<isize>,
+    diagonal: bool,
+    /// Cost of any step, plus this much per unit of height climbed or dropped.
+    step_cost: usize,
+    ascent_cost: usize,
+    descent_cost: usize,
+}
+
+/// The puzzle's hiker: climbs at most one, drops any distance, one step at a time.
+const PUZZLE_RULES: Rules = Rules {
+    max_ascent: 1,
+    max_descent: None,
+    diagonal: false,
+    step_cost: 1,
+    ascent_cost: 0,
+    descent_cost: 0,
+};
+
+impl Rules {
+    /// Cost of a step between these heights, if it is allowed at all.
+    fn cost(&self, from: isize, to: isize) -> Option<usize> {
+        let (climb, drop) = ((to - from).max(0), (from - to).max(0));
+        if climb > self.max_ascent || self.max_descent.is_some_and(|max| drop > max) {
+            return None;
+        }
+        Some(self.step_cost + self.ascent_cost * climb as usize + self.descent_cost * drop as usize)
+    }
+
+    /// A lower bound on the steps needed to get between cells this far apart, this much apart
+    /// in height.
+    fn min_steps(&self, d_row: isize, d_col: isize, climb: isize) -> usize {
+        let distance = if self.diagonal {
+            d_row.abs().max(d_col.abs())
+        } else {
+            d_row.abs() + d_col.abs()
+        };
+        let limit = if climb > 0 {
+            Some(self.max_ascent)
+        } else {
+            self.max_descent
+        };
+        let height_steps = match limit {
+            Some(limit) if limit > 0 => (climb.abs() + limit - 1) / limit,
+            _ => 0,
+        };
+        distance.max(height_steps) as usize
+    }
+}
+
 #[derive(Debug)]
 struct Map {
     heights: HashMap<Pos, isize>,
@@ -46,63 +104,443 @@ impl Map {
         }
     }
 
-    fn find_shortest_path(&self) -> usize {
-        let mut visited: HashSet<Pos> = HashSet::from_iter([self.start.clone()]);
-        let mut queue: VecDeque<(usize, Pos)> = VecDeque::from_iter([(0, self.start.clone())]);
+    /// Neighbours of `pos` on the map.
+    fn neighbours<'a>(
+        &'a self,
+        pos: &Pos,
+        rules: &Rules,
+    ) -> impl Iterator<Item = (Pos, isize)> + 'a {
+        let pos = pos.clone();
+        let diagonals = if rules.diagonal { 8 } else { 4 };
+        [
+            (-1isize, 0isize),
+            (1, 0),
+            (0, -1),
+            (0, 1),
+            (-1, -1),
+            (-1, 1),
+            (1, -1),
+            (1, 1),
+        ]
+        .into_iter()
+        .take(diagonals)
+        .filter_map(move |(d_row, d_col)| {
+            let other = Pos(pos.0 + d_row, pos.1 + d_col);
+            let height = *self.heights.get(&other)?;
+            Some((other, height))
+        })
+    }
 
-        while !queue.is_empty() {
-            let (steps, pos) = queue.pop_front().unwrap();
-            if pos == self.end {
-                return steps;
+    /// Steps needed to get from `origin` to every cell it can reach, or with `backwards`,
+    /// from every cell that can reach `origin`. Unreachable cells are left out.
+    fn distances(&self, origin: &Pos, backwards: bool, rules: &Rules) -> HashMap<Pos, usize> {
+        let mut distances = HashMap::from([(origin.clone(), 0)]);
+        let mut queue = VecDeque::from([origin.clone()]);
+        while let Some(pos) = queue.pop_front() {
+            let steps = distances[&pos];
+            let this_height = self.heights[&pos];
+            for (other, other_height) in self.neighbours(&pos, rules) {
+                let allowed = if backwards {
+                    rules.cost(other_height, this_height)
+                } else {
+                    rules.cost(this_height, other_height)
+                };
+                if allowed.is_some() && !distances.contains_key(&other) {
+                    distances.insert(other.clone(), steps + 1);
+                    queue.push_back(other);
+                }
             }
-            // dbg!(&steps, &pos);
-            for (d_row, d_col) in [(-1isize, 0isize), (1, 0), (0, -1), (0, 1)] {
-                let maybe_pos = Pos(pos.0 + d_row, pos.1 + d_col);
-                if visited.contains(&maybe_pos) {
-                    continue;
+        }
+        distances
+    }
+
+    /// Steps needed from every cell to reach the end, found in one go by searching backwards
+    /// from the end with the climbing rule reversed.
+    fn distances_to_end(&self, rules: &Rules) -> HashMap<Pos, usize> {
+        self.distances(&self.end, true, rules)
+    }
+
+    /// A path from `from` to `to`, both included, with the lowest total `cost` of its steps,
+    /// and that cost. `estimate` must never be more than the cost left to get to `to`.
+    fn best_path(
+        &self,
+        from: &Pos,
+        to: &Pos,
+        rules: &Rules,
+        cost: impl Fn(isize, isize) -> Option<usize>,
+        estimate: impl Fn(&Pos) -> usize,
+    ) -> Option<(usize, Vec<Pos>)> {
+        let mut best = HashMap::from([(from.clone(), 0)]);
+        let mut came_from = HashMap::new();
+        let mut queue = BinaryHeap::from([Reverse((estimate(from), 0, from.clone()))]);
+        while let Some(Reverse((_, total, pos))) = queue.pop() {
+            if pos == *to {
+                let mut path = vec![pos];
+                while let Some(prev) = came_from.get(path.last().unwrap()) {
+                    path.push(Pos::clone(prev));
                 }
-                let this_height = self.heights.get(&pos).unwrap();
-                if let Some(other_height) = self.heights.get(&maybe_pos) {
-                    if other_height - this_height <= 1 {
-                        visited.insert(maybe_pos.clone());
-                        queue.push_back((steps + 1, maybe_pos));
-                    }
+                path.reverse();
+                return Some((total, path));
+            }
+            if best[&pos] < total {
+                continue;
+            }
+            let this_height = self.heights[&pos];
+            for (other, other_height) in self.neighbours(&pos, rules) {
+                let Some(step) = cost(this_height, other_height) else {
+                    continue;
+                };
+                if best.get(&other).is_none_or(|&b| total + step < b) {
+                    best.insert(other.clone(), total + step);
+                    came_from.insert(other.clone(), pos.clone());
+                    queue.push(Reverse((
+                        total + step + estimate(&other),
+                        total + step,
+                        other,
+                    )));
                 }
             }
         }
-        // Did not find a path.
-        usize::MAX
+        None
+    }
+
+    /// A path from `from` to `to` with the fewest steps, found with A*.
+    fn shortest_path(&self, from: &Pos, to: &Pos, rules: &Rules) -> Option<Vec<Pos>> {
+        let estimate = |pos: &Pos| {
+            let climb = self.heights[to] - self.heights[pos];
+            rules.min_steps(to.0 - pos.0, to.1 - pos.1, climb)
+        };
+        let cost = |from, to| rules.cost(from, to).map(|_| 1);
+        let (_, path) = self.best_path(from, to, rules, cost, estimate)?;
+        Some(path)
+    }
+
+    /// A path from `from` to `to` with the lowest total cost under `rules`, and that cost,
+    /// found with Dijkstra.
+    fn cheapest_path(&self, from: &Pos, to: &Pos, rules: &Rules) -> Option<(usize, Vec<Pos>)> {
+        self.best_path(from, to, rules, |from, to| rules.cost(from, to), |_| 0)
+    }
+
+    fn size(&self) -> (isize, isize) {
+        let rows = self.heights.keys().map(|pos| pos.0).max().unwrap() + 1;
+        let cols = self.heights.keys().map(|pos| pos.1).max().unwrap() + 1;
+        (rows, cols)
+    }
+
+    /// Draws the path the way the puzzle does, with arrows pointing to the next step.
+    fn render_path(&self, path: &[Pos]) -> String {
+        let (rows, cols) = self.size();
+        let mut grid = vec![vec!['.'; cols as usize]; rows as usize];
+        for step in path.windows(2) {
+            let arrow = match (step[1].0 - step[0].0, step[1].1 - step[0].1) {
+                (0, 1) => '>',
+                (1, 0) => 'v',
+                (0, -1) => '<',
+                (-1, 0) => '^',
+                (-1, 1) | (1, -1) => '/',
+                _ => '\\',
+            };
+            grid[step[0].0 as usize][step[0].1 as usize] = arrow;
+        }
+        if let Some(last) = path.last() {
+            grid[last.0 as usize][last.1 as usize] = 'E';
+        }
+        grid.iter()
+            .map(|row| row.iter().collect::<String>() + "\n")
+            .collect()
+    }
+
+    /// The distances as CSV, one row per map row, with unreachable cells left empty.
+    fn distance_field_csv(&self, distances: &HashMap<Pos, usize>) -> String {
+        let (rows, cols) = self.size();
+        (0..rows)
+            .map(|row| {
+                let cells = (0..cols).map(|col| {
+                    distances
+                        .get(&Pos(row, col))
+                        .map_or(String::new(), |d| d.to_string())
+                });
+                cells.collect::<Vec<_>>().join(",") + "\n"
+            })
+            .collect()
     }
 }
 
 fn main() {
     let input = lib::read_input();
-    let mut map = Map::parse(&input);
-    // dbg!(&map);
+    let map = Map::parse(&input);
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let option = |name: &str| {
+        args.iter()
+            .find_map(|arg| arg.strip_prefix(&format!("{}=", name)))
+    };
+    let mut rules = PUZZLE_RULES;
+    if let Some(ascent) = option("ascent") {
+        rules.max_ascent = ascent.parse().expect("bad ascent");
+    }
+    if let Some(descent) = option("descent") {
+        rules.max_descent = Some(descent.parse().expect("bad descent"));
+    }
+    rules.diagonal = args.iter().any(|arg| arg == "diagonal");
+    if let Some(costs) = option("cost") {
+        let costs: Vec<usize> = costs
+            .split(',')
+            .map(|c| c.parse().expect("bad cost"))
+            .collect();
+        let [step, ascent, descent] = costs[..] else {
+            panic!("cost takes step,ascent,descent");
+        };
+        (rules.step_cost, rules.ascent_cost, rules.descent_cost) = (step, ascent, descent);
+        // None when the rules leave the end unreachable.
+        let cost = map
+            .cheapest_path(&map.start, &map.end, &rules)
+            .map(|(cost, _)| cost);
+        dbg!(cost);
+    }
+
     // Part 1:
-    let part1 = map.find_shortest_path();
+    let path = map.shortest_path(&map.start, &map.end, &rules);
+    let part1 = path.as_ref().map(|path| path.len() - 1);
     dbg!(&part1);
-    // Part 2:
-    // Find all possible starting locations with height 'a':
-    let a_poses: Vec<Pos> = map
+    // Part 2: the closest of all possible starting locations with height 'a'.
+    let distances = map.distances_to_end(&rules);
+    let part2 = map
         .heights
         .iter()
-        .filter_map(|(pos, height)| {
-            if height == &0 {
-                Some(pos.clone())
-            } else {
-                None
-            }
-        })
-        .collect();
-    // dbg!(&a_poses);
-    let part2 = a_poses
-        .into_iter()
-        .map(|pos| {
-            map.start = pos;
-            map.find_shortest_path()
-        })
-        .min()
-        .unwrap();
+        .filter(|(_, height)| **height == 0)
+        .filter_map(|(pos, _)| distances.get(pos))
+        .min();
     dbg!(&part2);
+
+    if let Some(path) = path.filter(|_| args.iter().any(|arg| arg == "path")) {
+        print!("{}", map.render_path(&path));
+    }
+    if let Some(file) = args.iter().find_map(|arg| arg.strip_prefix("field=")) {
+        let field = map.distance_field_csv(&map.distances(&map.start, false, &rules));
+        std::fs::write(file, field).expect("write failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    const SAMPLE: &str = "\
+Sabqponm
+abcryxxl
+accszExk
+acctuvwj
+abdefghi
+";
+
+    impl Map {
+        /// Plain BFS from `start`.
+        fn find_shortest_path(&self) -> usize {
+            let mut visited: HashSet<Pos> = HashSet::from_iter([self.start.clone()]);
+            let mut queue: VecDeque<(usize, Pos)> = VecDeque::from_iter([(0, self.start.clone())]);
+
+            while !queue.is_empty() {
+                let (steps, pos) = queue.pop_front().unwrap();
+                if pos == self.end {
+                    return steps;
+                }
+                // dbg!(&steps, &pos);
+                for (d_row, d_col) in [(-1isize, 0isize), (1, 0), (0, -1), (0, 1)] {
+                    let maybe_pos = Pos(pos.0 + d_row, pos.1 + d_col);
+                    if visited.contains(&maybe_pos) {
+                        continue;
+                    }
+                    let this_height = self.heights.get(&pos).unwrap();
+                    if let Some(other_height) = self.heights.get(&maybe_pos) {
+                        if other_height - this_height <= 1 {
+                            visited.insert(maybe_pos.clone());
+                            queue.push_back((steps + 1, maybe_pos));
+                        }
+                    }
+                }
+            }
+            // Did not find a path.
+            usize::MAX
+        }
+    }
+
+    /// Runs a BFS from every 'a' cell.
+    fn brute_force_part2(map: &mut Map) -> usize {
+        let a_poses: Vec<Pos> = map
+            .heights
+            .iter()
+            .filter(|(_, height)| **height == 0)
+            .map(|(pos, _)| pos.clone())
+            .collect();
+        a_poses
+            .into_iter()
+            .map(|pos| {
+                map.start = pos;
+                map.find_shortest_path()
+            })
+            .min()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sample() {
+        let mut map = Map::parse(SAMPLE);
+        let path = map
+            .shortest_path(&map.start, &map.end, &PUZZLE_RULES)
+            .unwrap();
+        assert_eq!(path.len(), 32);
+        assert_eq!(
+            map.render_path(&path),
+            "\
+>>vv<<<<
+..vvv<<^
+..vv>E^^
+..v>>>^^
+..>>>>>^
+"
+        );
+        assert_eq!(map.distances_to_end(&PUZZLE_RULES)[&map.start], 31);
+        assert_eq!(brute_force_part2(&mut map), 29);
+    }
+
+    #[test]
+    fn test_matches_brute_force() {
+        let mut rng = lib::Rng(0x2545f4914f6cdd1d);
+        let mut random = |n: u64| rng.below(n);
+        for _ in 0..100 {
+            let (rows, cols) = (1 + random(8), 2 + random(8));
+            let mut input: Vec<Vec<char>> = (0..rows)
+                .map(|_| {
+                    (0..cols)
+                        .map(|_| (b'a' + random(6) as u8) as char)
+                        .collect()
+                })
+                .collect();
+            input[0][0] = 'S';
+            input[random(rows) as usize][1 + random(cols - 1) as usize] = 'E';
+            let input: String = input
+                .iter()
+                .map(|row| row.iter().collect::<String>() + "\n")
+                .collect();
+            let mut map = Map::parse(&input);
+
+            let distances = map.distances_to_end(&PUZZLE_RULES);
+            for pos in map.heights.keys().cloned().collect::<Vec<_>>() {
+                map.start = pos.clone();
+                let expected = Some(map.find_shortest_path()).filter(|&d| d != usize::MAX);
+                assert_eq!(distances.get(&pos).copied(), expected, "{}", input);
+                let path = map.shortest_path(&pos, &map.end, &PUZZLE_RULES);
+                assert_eq!(path.as_ref().map(|p| p.len() - 1), expected, "{}", input);
+                for step in path.iter().flat_map(|p| p.windows(2)) {
+                    assert!(map
+                        .neighbours(&step[0], &PUZZLE_RULES)
+                        .any(|(next, height)| {
+                            next == step[1] && height - map.heights[&step[0]] <= 1
+                        }));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_distance_field() {
+        let map = Map::parse("Sbc\nzzE\n");
+        let distances = map.distances(&map.start, false, &PUZZLE_RULES);
+        assert_eq!(map.distance_field_csv(&distances), "0,1,2\n,,\n");
+        assert_eq!(map.shortest_path(&map.start, &map.end, &PUZZLE_RULES), None);
+    }
+
+    /// Cheapest cost from `from` to every cell, relaxing every step until nothing changes.
+    fn bellman_ford(map: &Map, from: &Pos, rules: &Rules) -> HashMap<Pos, usize> {
+        let mut costs = HashMap::from([(from.clone(), 0)]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (pos, height) in map.heights.iter() {
+                let Some(&cost) = costs.get(pos) else {
+                    continue;
+                };
+                for (other, other_height) in map.neighbours(pos, rules) {
+                    let Some(step) = rules.cost(*height, other_height) else {
+                        continue;
+                    };
+                    if costs.get(&other).is_none_or(|&c| cost + step < c) {
+                        costs.insert(other, cost + step);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        costs
+    }
+
+    #[test]
+    fn test_rules() {
+        let map = Map::parse(SAMPLE);
+        let diagonal = Rules {
+            diagonal: true,
+            ..PUZZLE_RULES
+        };
+        let path = map.shortest_path(&map.start, &map.end, &diagonal).unwrap();
+        assert_eq!(path.len() - 1, 27);
+        assert_eq!(map.distances(&map.start, false, &diagonal)[&map.end], 27);
+        assert_eq!(
+            map.cheapest_path(&map.start, &map.end, &PUZZLE_RULES)
+                .map(|(cost, _)| cost),
+            Some(31)
+        );
+
+        let mut rng = lib::Rng(0x9e3779b97f4a7c15);
+        let mut random = |n: u64| rng.below(n);
+        for _ in 0..100 {
+            let (rows, cols) = (1 + random(6), 2 + random(6));
+            let input: String = (0..rows)
+                .map(|row| {
+                    (0..cols)
+                        .map(|col| match (row, col) {
+                            (0, 0) => 'S',
+                            _ if (row, col) == (rows - 1, cols - 1) => 'E',
+                            _ => (b'a' + random(26) as u8) as char,
+                        })
+                        .collect::<String>()
+                        + "\n"
+                })
+                .collect();
+            let map = Map::parse(&input);
+            let rules = Rules {
+                max_ascent: random(8) as isize,
+                max_descent: [None, Some(random(8) as isize)][random(2) as usize],
+                diagonal: random(2) == 0,
+                step_cost: random(4) as usize,
+                ascent_cost: random(4) as usize,
+                descent_cost: random(4) as usize,
+            };
+
+            let costs = bellman_ford(&map, &map.start, &rules);
+            let steps = map.distances(&map.start, false, &rules);
+            assert_eq!(
+                map.cheapest_path(&map.start, &map.end, &rules)
+                    .map(|(cost, _)| cost),
+                costs.get(&map.end).copied(),
+                "{}{:?}",
+                input,
+                rules
+            );
+            assert_eq!(
+                map.shortest_path(&map.start, &map.end, &rules)
+                    .map(|path| path.len() - 1),
+                steps.get(&map.end).copied(),
+                "{}{:?}",
+                input,
+                rules
+            );
+            let to_end = map.distances_to_end(&rules);
+            for (pos, steps) in to_end.iter() {
+                let forward = map.distances(pos, false, &rules);
+                assert_eq!(forward.get(&map.end), Some(steps));
+            }
+        }
+    }
 }
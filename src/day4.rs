@@ -1,15 +1,196 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
 mod lib;
 
-fn parse_range(s: &str) -> (u32, u32) {
+type Range = (u32, u32);
+
+fn parse_range(s: &str) -> Range {
     let (start, end) = s.split_once("-").unwrap();
     (start.parse::<u32>().unwrap(), end.parse::<u32>().unwrap())
 }
 
-fn parse_line(line: &str) -> ((u32, u32), (u32, u32)) {
+fn parse_line(line: &str) -> (Range, Range) {
     let (a, b) = line.split_once(",").unwrap();
     (parse_range(a), parse_range(b))
 }
 
+/// All section assignments across all lines. Line `i` holds elves `2 * i` and `2 * i + 1`.
+struct Assignments {
+    ranges: Vec<Range>,
+}
+
+impl Assignments {
+    fn new(lines: &[(Range, Range)]) -> Assignments {
+        Assignments {
+            ranges: lines.iter().flat_map(|(a, b)| [*a, *b]).collect(),
+        }
+    }
+
+    /// Counts pairs of assignments from different lines that share at least one section.
+    fn count_overlapping_pairs(&self) -> usize {
+        // Sweep by start, keeping the ends of the ranges that are still open in a min-heap.
+        let mut order: Vec<usize> = (0..self.ranges.len()).collect();
+        order.sort_by_key(|&i| self.ranges[i].0);
+        let mut open = BinaryHeap::new();
+        let mut pairs = 0;
+        for i in order {
+            let (start, end) = self.ranges[i];
+            while let Some(Reverse(open_end)) = open.peek() {
+                if *open_end >= start {
+                    break;
+                }
+                open.pop();
+            }
+            pairs += open.len();
+            open.push(Reverse(end));
+        }
+        // The sweep also counted the two elves on the same line; those are part 2's business.
+        let same_line = self
+            .ranges
+            .chunks(2)
+            .filter(|pair| pair[1].0 <= pair[0].1 && pair[0].0 <= pair[1].1)
+            .count();
+        pairs - same_line
+    }
+
+    /// Returns maximal runs of sections `(first, last, depth)` that are covered by the same,
+    /// non-zero number of assignments.
+    fn coverage(&self) -> Vec<(u32, u32, usize)> {
+        let mut events: Vec<(u32, isize)> = self
+            .ranges
+            .iter()
+            .flat_map(|(start, end)| [(*start, 1), (end + 1, -1)])
+            .collect();
+        events.sort_unstable();
+
+        let mut out: Vec<(u32, u32, usize)> = Vec::new();
+        let mut depth = 0isize;
+        let mut i = 0;
+        while i < events.len() {
+            let section = events[i].0;
+            while i < events.len() && events[i].0 == section {
+                depth += events[i].1;
+                i += 1;
+            }
+            if depth == 0 || i == events.len() {
+                continue;
+            }
+            let last = events[i].0 - 1;
+            match out.last_mut() {
+                // An opening and a closing at the same section leave the depth unchanged.
+                Some(run) if run.2 == depth as usize && run.1 + 1 == section => run.1 = last,
+                _ => out.push((section, last, depth as usize)),
+            }
+        }
+        out
+    }
+
+    /// Returns the first section with the highest coverage depth, and that depth.
+    fn max_depth(&self) -> Option<(u32, usize)> {
+        self.coverage()
+            .into_iter()
+            .max_by_key(|(first, _, depth)| (*depth, Reverse(*first)))
+            .map(|(first, _, depth)| (first, depth))
+    }
+
+    /// Returns runs of sections within `first..=last` that nobody is assigned to.
+    fn uncovered(&self, first: u32, last: u32) -> Vec<Range> {
+        let mut out = Vec::new();
+        let mut next = first;
+        for (start, end, _) in self.coverage() {
+            if start > last {
+                break;
+            }
+            if start > next {
+                out.push((next, start - 1));
+            }
+            next = next.max(end + 1);
+        }
+        if next <= last {
+            out.push((next, last));
+        }
+        out
+    }
+
+    /// Whether elf `a`'s range fully contains elf `b`'s. Identical ranges are ordered by elf
+    /// number, so that containment is a strict partial order.
+    fn contains(&self, a: usize, b: usize) -> bool {
+        let ((a_start, a_end), (b_start, b_end)) = (self.ranges[a], self.ranges[b]);
+        a != b
+            && a_start <= b_start
+            && a_end >= b_end
+            && (self.ranges[a] != self.ranges[b] || a < b)
+    }
+
+    /// Finds a smallest set of elves whose removal leaves no range fully containing another.
+    ///
+    /// The elves that stay must form an antichain of the containment order. The largest antichain
+    /// falls out of König's theorem applied to a maximum matching of the order's bipartite split.
+    fn min_containment_removal(&self) -> Vec<usize> {
+        let n = self.ranges.len();
+        let contained: Vec<Vec<usize>> = (0..n)
+            .map(|a| (0..n).filter(|&b| self.contains(a, b)).collect())
+            .collect();
+
+        fn augment(
+            a: usize,
+            contained: &[Vec<usize>],
+            match_of: &mut [Option<usize>],
+            seen: &mut [bool],
+        ) -> bool {
+            for &b in &contained[a] {
+                if seen[b] {
+                    continue;
+                }
+                seen[b] = true;
+                if match_of[b].is_none_or(|a2| augment(a2, contained, match_of, seen)) {
+                    match_of[b] = Some(a);
+                    return true;
+                }
+            }
+            false
+        }
+
+        // match_of[b] is the elf whose containment edge into b is matched.
+        let mut match_of = vec![None; n];
+        let mut matched = vec![false; n];
+        let mut seen = vec![false; n];
+        for (a, is_matched) in matched.iter_mut().enumerate() {
+            // A failed search can't succeed later on in the same pass, so `seen` only needs
+            // clearing after a successful augmentation.
+            if augment(a, &contained, &mut match_of, &mut seen) {
+                *is_matched = true;
+                seen.iter_mut().for_each(|s| *s = false);
+            }
+        }
+
+        // Follow alternating paths from unmatched left vertices.
+        let mut reach_left = vec![false; n];
+        let mut reach_right = vec![false; n];
+        let mut stack: Vec<usize> = (0..n).filter(|&a| !matched[a]).collect();
+        stack.iter().for_each(|&a| reach_left[a] = true);
+        while let Some(a) = stack.pop() {
+            for &b in &contained[a] {
+                if reach_right[b] {
+                    continue;
+                }
+                reach_right[b] = true;
+                if let Some(a2) = match_of[b] {
+                    if !reach_left[a2] {
+                        reach_left[a2] = true;
+                        stack.push(a2);
+                    }
+                }
+            }
+        }
+        // The minimum vertex cover is the unreached left side plus the reached right side. Elves
+        // in neither half of the cover form a maximum antichain; everyone else has to go.
+        (0..n)
+            .filter(|&x| !reach_left[x] || reach_right[x])
+            .collect()
+    }
+}
+
 fn main() {
     let input = lib::read_input();
     let ranges = input.lines().map(parse_line).collect::<Vec<_>>();
@@ -36,4 +217,75 @@ fn main() {
         })
         .sum::<u32>();
     dbg!(part2);
+
+    if std::env::args().nth(2).as_deref() == Some("analyze") {
+        let assignments = Assignments::new(&ranges);
+        dbg!(assignments.count_overlapping_pairs());
+        dbg!(assignments.max_depth());
+        dbg!(assignments.coverage());
+        let max_section = assignments.ranges.iter().map(|r| r.1).max().unwrap_or(0);
+        dbg!(assignments.uncovered(1, max_section));
+        let removal = assignments.min_containment_removal();
+        dbg!(removal.len(), &removal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Assignments {
+        let input = "2-4,6-8\n2-3,4-5\n5-7,7-9\n2-8,3-7\n6-6,4-6\n2-6,4-8";
+        Assignments::new(&input.lines().map(parse_line).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_overlapping_pairs_matches_brute_force() {
+        let a = sample();
+        let n = a.ranges.len();
+        let brute = (0..n)
+            .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+            .filter(|&(i, j)| i / 2 != j / 2)
+            .filter(|&(i, j)| a.ranges[i].0 <= a.ranges[j].1 && a.ranges[j].0 <= a.ranges[i].1)
+            .count();
+        assert_eq!(a.count_overlapping_pairs(), brute);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let a = Assignments::new(&[((1, 3), (3, 5)), ((8, 8), (9, 9))]);
+        assert_eq!(
+            a.coverage(),
+            vec![(1, 2, 1), (3, 3, 2), (4, 5, 1), (8, 9, 1)]
+        );
+        assert_eq!(a.max_depth(), Some((3, 2)));
+        assert_eq!(a.uncovered(0, 10), vec![(0, 0), (6, 7), (10, 10)]);
+    }
+
+    #[test]
+    fn test_min_containment_removal() {
+        let a = sample();
+        let removal = a.min_containment_removal();
+        let kept: Vec<usize> = (0..a.ranges.len())
+            .filter(|x| !removal.contains(x))
+            .collect();
+        for &x in &kept {
+            for &y in &kept {
+                assert!(!a.contains(x, y));
+            }
+        }
+        // Check minimality against every subset of the sample's 12 elves.
+        let n = a.ranges.len();
+        let best = (0u32..1 << n)
+            .filter(|mask| {
+                (0..n).all(|x| {
+                    (0..n)
+                        .all(|y| mask & (1 << x) != 0 || mask & (1 << y) != 0 || !a.contains(x, y))
+                })
+            })
+            .map(|mask| mask.count_ones() as usize)
+            .min()
+            .unwrap();
+        assert_eq!(removal.len(), best);
+    }
 }
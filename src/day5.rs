@@ -1,85 +1,1097 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fmt,
+    rc::Rc,
+};
+
 mod lib;
 
-type CrateStack = Vec<Vec<char>>;
-
-// [T]             [P]     [J]
-// [F]     [S]     [T]     [R]     [B]
-// [V]     [M] [H] [S]     [F]     [R]
-// [Z]     [P] [Q] [B]     [S] [W] [P]
-// [C]     [Q] [R] [D] [Z] [N] [H] [Q]
-// [W] [B] [T] [F] [L] [T] [M] [F] [T]
-// [S] [R] [Z] [V] [G] [R] [Q] [N] [Z]
-// [Q] [Q] [B] [D] [J] [W] [H] [R] [J]
-//  1   2   3   4   5   6   7   8   9
-fn parse_stack(text: &str) -> CrateStack {
-    // Ignore the last line and assume it's 1, 2, 3...
-    let lines: Vec<&str> = text.lines().rev().skip(1).collect();
-    let count = (lines[0].len() + 1) / 4;
-
-    let mut out = Vec::new();
-    out.resize_with(count, || Vec::new());
-    for l in lines.iter() {
-        for i in 0..count {
-            let c = l.chars().nth(4 * i + 1).unwrap();
-            if c == ' ' {
-                continue;
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CrateStack {
+    /// Stack labels from the diagram's footer, left to right.
+    ids: Vec<usize>,
+    /// Crates in each stack, bottom first.
+    stacks: Vec<Vec<char>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum DiagramError {
+    /// There's no footer line.
+    Empty,
+    /// A footer slot isn't a number.
+    BadLabel {
+        column: usize,
+        text: String,
+    },
+    DuplicateLabel(usize),
+    /// A label with more than 3 digits doesn't fit its slot.
+    WideLabel(usize),
+    /// A crate that isn't a single printable ASCII character other than `]`.
+    InvalidCrate(char),
+    /// Something other than `[X]` or blanks in a crate slot. Lines count from 1 at the top.
+    BadCrate {
+        line: usize,
+        column: usize,
+    },
+    /// A crate with an empty slot below it.
+    Floating {
+        line: usize,
+        column: usize,
+    },
+}
+
+impl fmt::Display for DiagramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagramError::Empty => write!(f, "empty diagram"),
+            DiagramError::BadLabel { column, text } => {
+                write!(f, "column {}: bad stack label {:?}", column, text)
+            }
+            DiagramError::DuplicateLabel(id) => write!(f, "stack {} appears twice", id),
+            DiagramError::WideLabel(id) => write!(f, "stack label {} is too wide", id),
+            DiagramError::InvalidCrate(c) => write!(f, "crate {:?} can't be drawn", c),
+            DiagramError::BadCrate { line, column } => {
+                write!(f, "line {}, column {}: bad crate", line, column)
+            }
+            DiagramError::Floating { line, column } => {
+                write!(f, "line {}, column {}: crate is floating", line, column)
+            }
+        }
+    }
+}
+
+impl CrateStack {
+    /// Checks that the stacks can be written as a diagram, so that `parse` reads back exactly what
+    /// `Display` writes: at least one stack, distinct labels of at most 3 digits, and crates that
+    /// are printable ASCII other than `]`.
+    fn new(ids: Vec<usize>, stacks: Vec<Vec<char>>) -> Result<CrateStack, DiagramError> {
+        assert_eq!(ids.len(), stacks.len());
+        if ids.is_empty() {
+            return Err(DiagramError::Empty);
+        }
+        for (i, &id) in ids.iter().enumerate() {
+            if id >= 1000 {
+                return Err(DiagramError::WideLabel(id));
+            }
+            if ids[..i].contains(&id) {
+                return Err(DiagramError::DuplicateLabel(id));
+            }
+        }
+        if let Some(&c) = stacks
+            .iter()
+            .flatten()
+            .find(|c| !c.is_ascii_graphic() || **c == ']')
+        {
+            return Err(DiagramError::InvalidCrate(c));
+        }
+        Ok(CrateStack { ids, stacks })
+    }
+
+    // [T]             [P]     [J]
+    // [F]     [S]     [T]     [R]     [B]
+    // [V]     [M] [H] [S]     [F]     [R]
+    // [Z]     [P] [Q] [B]     [S] [W] [P]
+    // [C]     [Q] [R] [D] [Z] [N] [H] [Q]
+    // [W] [B] [T] [F] [L] [T] [M] [F] [T]
+    // [S] [R] [Z] [V] [G] [R] [Q] [N] [Z]
+    // [Q] [Q] [B] [D] [J] [W] [H] [R] [J]
+    //  1   2   3   4   5   6   7   8   9
+    //
+    // Every stack takes a 3 character slot plus a separating space. The footer's labels don't
+    // have to be 1, 2, 3..., and can have up to 3 digits.
+    fn parse(text: &str) -> Result<CrateStack, DiagramError> {
+        let lines: Vec<Vec<char>> = text.lines().map(|l| l.chars().collect()).collect();
+        let (footer, crate_lines) = lines.split_last().ok_or(DiagramError::Empty)?;
+        // Slot `i` is at `4 * i..4 * i + 3`; a slot cut short by trimmed whitespace counts too.
+        let slot = |line: &[char], i: usize| -> String {
+            (4 * i..4 * i + 3)
+                .map(|pos| line.get(pos).cloned().unwrap_or(' '))
+                .collect()
+        };
+        let count = footer.len().div_ceil(4);
+
+        let mut ids = Vec::new();
+        for column in 0..count {
+            let text = slot(footer, column);
+            let id = text
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| DiagramError::BadLabel { column, text })?;
+            ids.push(id);
+        }
+
+        let mut stacks = vec![Vec::new(); count];
+        for (line, chars) in crate_lines.iter().enumerate().rev() {
+            if chars.iter().skip(4 * count).any(|c| *c != ' ') {
+                return Err(DiagramError::BadCrate {
+                    line: line + 1,
+                    column: count,
+                });
             }
-            out[i].push(c);
+            for (column, stack) in stacks.iter_mut().enumerate() {
+                let below = crate_lines.len() - 1 - line;
+                match slot(chars, column).as_bytes() {
+                    b"   " => {}
+                    [b'[', c, b']'] if !c.is_ascii_graphic() || *c == b']' => {
+                        return Err(DiagramError::BadCrate {
+                            line: line + 1,
+                            column,
+                        })
+                    }
+                    [b'[', _, b']'] if stack.len() < below => {
+                        return Err(DiagramError::Floating {
+                            line: line + 1,
+                            column,
+                        })
+                    }
+                    [b'[', _, b']'] => stack.push(chars[4 * column + 1]),
+                    _ => {
+                        return Err(DiagramError::BadCrate {
+                            line: line + 1,
+                            column,
+                        })
+                    }
+                }
+            }
+        }
+        CrateStack::new(ids, stacks)
+    }
+
+    /// Index into `stacks` for the stack labeled `id`.
+    fn index(&self, id: usize) -> Option<usize> {
+        self.ids.iter().position(|x| *x == id)
+    }
+
+    fn get(&self, id: usize) -> Option<&Vec<char>> {
+        self.index(id).map(|i| &self.stacks[i])
+    }
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut Vec<char>> {
+        self.index(id).map(|i| &mut self.stacks[i])
+    }
+}
+
+/// Writes the stacks back in the puzzle's diagram format, without a trailing newline.
+impl fmt::Display for CrateStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let height = self.stacks.iter().map(|s| s.len()).max().unwrap_or(0);
+        for level in (0..height).rev() {
+            let line: Vec<String> = self
+                .stacks
+                .iter()
+                .map(|stack| match stack.get(level) {
+                    Some(c) => format!("[{}]", c),
+                    None => "   ".to_string(),
+                })
+                .collect();
+            writeln!(f, "{}", line.join(" "))?;
         }
+        let footer: Vec<String> = self.ids.iter().map(|id| format!("{:^3}", id)).collect();
+        write!(f, "{}", footer.join(" "))
     }
-    out
 }
 
-// move 3 from 8 to 2
-fn parse_move(line: &str) -> (usize, usize, usize) {
-    let items: Vec<&str> = line.split(" ").collect();
-    let count = items[1].parse::<usize>().unwrap();
-    let from = items[3].parse::<usize>().unwrap();
-    let to = items[5].parse::<usize>().unwrap();
-    (count, from, to)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Move {
+    count: usize,
+    from: usize,
+    to: usize,
 }
 
-fn parse_and_apply_moves_part1(crate_stack: &mut CrateStack, moves_text: &str) {
-    for move_line in moves_text.lines() {
-        let (mut count, from, to) = parse_move(move_line);
-        while count > 0 {
-            let elem = crate_stack[from - 1].pop();
-            match elem {
-                Some(c) => crate_stack[to - 1].push(c),
-                None => break,
+#[derive(Debug, PartialEq, Eq)]
+enum MoveError {
+    /// The line isn't of the form "move N from A to B".
+    Malformed {
+        line: usize,
+        text: String,
+    },
+    NoSuchStack {
+        line: usize,
+        stack: usize,
+    },
+    NotEnoughCrates {
+        line: usize,
+        stack: usize,
+        have: usize,
+        want: usize,
+    },
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoveError::Malformed { line, text } => {
+                write!(f, "line {}: malformed move {:?}", line, text)
+            }
+            MoveError::NoSuchStack { line, stack } => {
+                write!(f, "line {}: there is no stack {}", line, stack)
             }
-            count -= 1;
+            MoveError::NotEnoughCrates {
+                line,
+                stack,
+                have,
+                want,
+            } => write!(
+                f,
+                "line {}: stack {} has {} crates, can't move {}",
+                line, stack, have, want
+            ),
         }
     }
 }
 
-fn parse_and_apply_moves_part2(crate_stack: &mut CrateStack, moves_text: &str) {
-    for move_line in moves_text.lines() {
-        let (count, from, to) = parse_move(move_line);
-        let from_pos = crate_stack[from - 1].len() - count;
-        let to_move: Vec<char> = crate_stack[from - 1].drain(from_pos..).collect();
-        crate_stack[to - 1].extend(to_move);
+impl Move {
+    // move 3 from 8 to 2
+    fn parse(line: usize, text: &str) -> Result<Move, MoveError> {
+        let malformed = || MoveError::Malformed {
+            line,
+            text: text.to_string(),
+        };
+        let items: Vec<&str> = text.split(" ").collect();
+        if items.len() != 6 || items[0] != "move" || items[2] != "from" || items[4] != "to" {
+            return Err(malformed());
+        }
+        let number = |s: &str| s.parse::<usize>().map_err(|_| malformed());
+        Ok(Move {
+            count: number(items[1])?,
+            from: number(items[3])?,
+            to: number(items[5])?,
+        })
+    }
+
+    /// Checks that the move can be carried out on `crate_stack`.
+    fn validate(&self, line: usize, crate_stack: &CrateStack) -> Result<(), MoveError> {
+        for stack in [self.from, self.to] {
+            if crate_stack.index(stack).is_none() {
+                return Err(MoveError::NoSuchStack { line, stack });
+            }
+        }
+        let have = crate_stack.get(self.from).unwrap().len();
+        if have < self.count {
+            return Err(MoveError::NotEnoughCrates {
+                line,
+                stack: self.from,
+                have,
+                want: self.count,
+            });
+        }
+        Ok(())
     }
 }
 
+/// Parses the move list. `first_line` is the line number of its first line in the input file.
+fn parse_moves(moves_text: &str, first_line: usize) -> Result<Vec<(usize, Move)>, MoveError> {
+    moves_text
+        .lines()
+        .enumerate()
+        .map(|(i, text)| Move::parse(first_line + i, text).map(|mv| (first_line + i, mv)))
+        .collect()
+}
+
+/// What a crane did for a single move: every group of crates it picked up, bottom crate first.
+#[derive(Debug)]
+struct MoveLog {
+    mv: Move,
+    lifts: Vec<Vec<char>>,
+}
+
+trait Crane {
+    /// How many crates the crane picks up at once when `remaining` crates still have to go.
+    fn lift_size(&self, remaining: usize) -> usize;
+
+    /// `lift_size`, checked so that a bad crane can't stall a move or lift crates that aren't
+    /// part of it.
+    fn checked_lift_size(&self, remaining: usize) -> usize {
+        let size = self.lift_size(remaining);
+        assert!(
+            (1..=remaining).contains(&size),
+            "crane lifted {} of {} crates",
+            size,
+            remaining
+        );
+        size
+    }
+
+    /// Moves crates lift by lift. Crates picked up together keep their order. The move must have
+    /// passed `Move::validate`.
+    fn apply(&self, crate_stack: &mut CrateStack, mv: Move) -> MoveLog {
+        let mut lifts = Vec::new();
+        let mut remaining = mv.count;
+        while remaining > 0 {
+            let from_stack = crate_stack.get_mut(mv.from).unwrap();
+            let size = self.checked_lift_size(remaining);
+            let lift: Vec<char> = from_stack.drain(from_stack.len() - size..).collect();
+            crate_stack.get_mut(mv.to).unwrap().extend(&lift);
+            lifts.push(lift);
+            remaining -= size;
+        }
+        MoveLog { mv, lifts }
+    }
+
+    /// Same as `apply`, without keeping the lifts.
+    fn shift(&self, crate_stack: &mut CrateStack, mv: Move) {
+        let indices = [mv.from, mv.to].map(|id| crate_stack.index(id).unwrap());
+        let [from, to] = crate_stack.stacks.get_disjoint_mut(indices).unwrap();
+        let mut remaining = mv.count;
+        while remaining > 0 {
+            let size = self.checked_lift_size(remaining);
+            to.extend_from_slice(&from[from.len() - size..]);
+            from.truncate(from.len() - size);
+            remaining -= size;
+        }
+    }
+}
+
+/// Moves one crate at a time.
+struct CrateMover9000;
+
+impl Crane for CrateMover9000 {
+    fn lift_size(&self, _remaining: usize) -> usize {
+        1
+    }
+}
+
+/// Moves all crates at once.
+struct CrateMover9001;
+
+impl Crane for CrateMover9001 {
+    fn lift_size(&self, remaining: usize) -> usize {
+        remaining
+    }
+}
+
+/// Moves at most `capacity` crates at once, splitting up larger moves.
+struct CapacityCrane {
+    capacity: usize,
+}
+
+impl CapacityCrane {
+    /// None for a capacity of 0, which could never move anything.
+    fn new(capacity: usize) -> Option<CapacityCrane> {
+        (capacity > 0).then_some(CapacityCrane { capacity })
+    }
+}
+
+impl Crane for CapacityCrane {
+    fn lift_size(&self, remaining: usize) -> usize {
+        remaining.min(self.capacity)
+    }
+}
+
+/// Parses a crane name from the command line: "9000", "9001" or "capacity=K" with K > 0.
+fn parse_crane(name: &str) -> Option<Box<dyn Crane>> {
+    match name {
+        "9000" => Some(Box::new(CrateMover9000)),
+        "9001" => Some(Box::new(CrateMover9001)),
+        _ => name
+            .strip_prefix("capacity=")
+            .and_then(|k| k.parse::<usize>().ok())
+            .and_then(CapacityCrane::new)
+            .map(|crane| Box::new(crane) as Box<dyn Crane>),
+    }
+}
+
+/// Every configuration the stacks have been in, so that moves can be undone and redone.
+struct History {
+    /// `states[n]` is the configuration after `n` moves.
+    states: Vec<CrateStack>,
+    /// How many moves are currently applied. Later states can be redone.
+    current: usize,
+}
+
+impl History {
+    fn new(initial: CrateStack) -> History {
+        History {
+            states: vec![initial],
+            current: 0,
+        }
+    }
+
+    fn current(&self) -> &CrateStack {
+        &self.states[self.current]
+    }
+
+    /// Validates and applies a move. Any undone moves are forgotten.
+    fn apply(&mut self, crane: &dyn Crane, line: usize, mv: Move) -> Result<MoveLog, MoveError> {
+        mv.validate(line, self.current())?;
+        let mut next = self.current().clone();
+        let log = crane.apply(&mut next, mv);
+        self.states.truncate(self.current + 1);
+        self.states.push(next);
+        self.current += 1;
+        Ok(log)
+    }
+
+    fn undo(&mut self) -> bool {
+        if self.current == 0 {
+            return false;
+        }
+        self.current -= 1;
+        true
+    }
+
+    fn redo(&mut self) -> bool {
+        if self.current + 1 == self.states.len() {
+            return false;
+        }
+        self.current += 1;
+        true
+    }
+
+    /// The configuration after `moves` moves, if they have been applied (or can be redone).
+    fn after(&self, moves: usize) -> Option<&CrateStack> {
+        self.states.get(moves)
+    }
+}
+
+fn apply_moves(
+    crane: &dyn Crane,
+    crate_stack: CrateStack,
+    moves: &[(usize, Move)],
+) -> Result<(History, Vec<MoveLog>), MoveError> {
+    let mut history = History::new(crate_stack);
+    let logs = moves
+        .iter()
+        .map(|(line, mv)| history.apply(crane, *line, *mv))
+        .collect::<Result<_, _>>()?;
+    Ok((history, logs))
+}
+
+/// Top crate of every stack, with a space for an empty one so that each character lines up with
+/// its stack.
 fn answer(crate_stack: &CrateStack) -> String {
-    let mut out = String::new();
-    for stack in crate_stack.iter() {
-        out.push(*stack.last().unwrap());
+    crate_stack
+        .stacks
+        .iter()
+        .map(|stack| stack.last().cloned().unwrap_or(' '))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Goal {
+    /// Same top crate on every stack.
+    Top,
+    /// Same crates everywhere.
+    Full,
+}
+
+impl Goal {
+    fn reached(&self, crate_stack: &CrateStack, target: &CrateStack) -> bool {
+        match self {
+            Goal::Top => crate_stack
+                .stacks
+                .iter()
+                .zip(&target.stacks)
+                .all(|(a, b)| a.last() == b.last()),
+            Goal::Full => crate_stack == target,
+        }
+    }
+
+    /// What one stack still needs, as two counts that add up over all stacks.
+    fn needs(&self, stack: &[char], wanted: &[char]) -> (usize, usize) {
+        match self {
+            // Whether the top crate is wrong, and whether the wanted one has to be given to the
+            // stack because it isn't anywhere in it.
+            Goal::Top => {
+                let wrong = stack.last() != wanted.last();
+                let given = wrong && wanted.last().is_some_and(|c| !stack.contains(c));
+                (wrong as usize, given as usize)
+            }
+            // Whether crates have to come off, and whether some have to go on.
+            Goal::Full => {
+                let common = stack.iter().zip(wanted).take_while(|(x, y)| x == y).count();
+                (
+                    (stack.len() > common) as usize,
+                    (wanted.len() > common) as usize,
+                )
+            }
+        }
+    }
+
+    /// Lower bound on the number of moves still needed, from the needs of all stacks. Every move
+    /// takes from one stack and adds to one other, and the bound never drops by more than one per
+    /// move.
+    fn combine(&self, (first, second): (usize, usize)) -> usize {
+        match self {
+            // A move changes the top of at most two stacks, and only one of them by putting
+            // crates on it.
+            Goal::Top => second.max(first.div_ceil(2)),
+            Goal::Full => first.max(second),
+        }
+    }
+
+    fn estimate(&self, crate_stack: &CrateStack, target: &CrateStack) -> usize {
+        let needs = crate_stack.stacks.iter().zip(&target.stacks);
+        self.combine(needs.fold((0, 0), |(first, second), (stack, wanted)| {
+            let (a, b) = self.needs(stack, wanted);
+            (first + a, second + b)
+        }))
+    }
+}
+
+/// Finds a shortest list of moves that takes `initial` to `target` (or to its top crates) with
+/// the given crane, using A* over stack configurations. Gives up and returns None after
+/// expanding `limit` configurations.
+fn optimize(
+    crane: &dyn Crane,
+    initial: &CrateStack,
+    target: &CrateStack,
+    goal: Goal,
+    limit: usize,
+) -> Option<Vec<Move>> {
+    // Moves whose estimated total is above `bound` are left out, and the search starts over with
+    // the smallest such total if nothing within the bound reaches the target. That keeps the queue
+    // to moves that can still be part of a shortest plan.
+    let mut bound = goal.estimate(initial, target);
+    let mut expanded = 0;
+    loop {
+        // Every expanded configuration is stored once and referred to by its index, along with
+        // the move that reached it. The estimate never drops by more than the move it skips, so
+        // the first time a configuration comes off the queue is also the shortest way to it.
+        let mut states: Vec<Rc<CrateStack>> = Vec::new();
+        let mut index: HashMap<Rc<CrateStack>, usize> = HashMap::new();
+        let mut parents: Vec<Option<(usize, Move)>> = Vec::new();
+        // Moves still to try from an expanded configuration, cheapest estimate first. Ties go to
+        // the longest so far, which reaches one of many equally short answers sooner.
+        let mut queue = BinaryHeap::from([Reverse((bound, Reverse(0), None::<(usize, Move)>))]);
+        let mut next_bound: Option<usize> = None;
+
+        while let Some(Reverse((_, Reverse(steps), parent))) = queue.pop() {
+            let mut crate_stack = match parent {
+                Some((prev, _)) => (*states[prev]).clone(),
+                None => initial.clone(),
+            };
+            if let Some((_, mv)) = parent {
+                crane.shift(&mut crate_stack, mv);
+            }
+            if index.contains_key(&crate_stack) {
+                continue;
+            }
+            let idx = states.len();
+            let crate_stack = Rc::new(crate_stack);
+            states.push(crate_stack.clone());
+            index.insert(crate_stack.clone(), idx);
+            parents.push(parent);
+
+            if goal.reached(&crate_stack, target) {
+                let mut moves = Vec::new();
+                let mut idx = idx;
+                while let Some((prev, mv)) = parents[idx] {
+                    moves.push(mv);
+                    idx = prev;
+                }
+                moves.reverse();
+                return Some(moves);
+            }
+            expanded += 1;
+            if expanded > limit {
+                return None;
+            }
+            // Each move is tried on the same copy and then undone, which is cheaper than a copy
+            // per move. Only the two stacks it touches need a new estimate.
+            let mut scratch = (*crate_stack).clone();
+            let needs: Vec<(usize, usize)> = crate_stack
+                .stacks
+                .iter()
+                .zip(&target.stacks)
+                .map(|(stack, wanted)| goal.needs(stack, wanted))
+                .collect();
+            let total = needs
+                .iter()
+                .fold((0, 0), |(first, second), (a, b)| (first + a, second + b));
+            for (from_idx, from_stack) in crate_stack.stacks.iter().enumerate() {
+                for to_idx in 0..crate_stack.ids.len() {
+                    if to_idx == from_idx {
+                        continue;
+                    }
+                    for count in 1..=from_stack.len() {
+                        let mv = Move {
+                            count,
+                            from: crate_stack.ids[from_idx],
+                            to: crate_stack.ids[to_idx],
+                        };
+                        crane.shift(&mut scratch, mv);
+                        let (mut first, mut second) = total;
+                        for i in [from_idx, to_idx] {
+                            let (a, b) = goal.needs(&scratch.stacks[i], &target.stacks[i]);
+                            first = first + a - needs[i].0;
+                            second = second + b - needs[i].1;
+                        }
+                        let estimate = steps + 1 + goal.combine((first, second));
+                        scratch.stacks[to_idx].truncate(crate_stack.stacks[to_idx].len());
+                        scratch.stacks[from_idx]
+                            .extend_from_slice(&from_stack[from_stack.len() - count..]);
+                        if estimate > bound {
+                            next_bound = Some(next_bound.map_or(estimate, |b| b.min(estimate)));
+                            continue;
+                        }
+                        queue.push(Reverse((estimate, Reverse(steps + 1), Some((idx, mv)))));
+                    }
+                }
+            }
+        }
+        // Nothing left within any bound means the target can't be reached at all.
+        bound = next_bound?;
     }
-    out
 }
 
 fn main() {
     let input = lib::read_input();
     let (initial_state, moves) = input.split_once("\n\n").unwrap();
-    let crate_stack = parse_stack(initial_state);
+    let crate_stack = CrateStack::parse(initial_state).unwrap_or_else(|e| panic!("{}", e));
+    let moves =
+        parse_moves(moves, initial_state.lines().count() + 2).unwrap_or_else(|e| panic!("{}", e));
+
+    if std::env::args().nth(2).as_deref() == Some("optimize") {
+        // `top` reaches the input's answers within its default limit. `full` queues far more
+        // moves per configuration, so it only finishes for small rearrangements and gives up on
+        // the input's before running out of memory.
+        let (goal, default_limit) = match std::env::args().nth(3).as_deref() {
+            Some("full") => (Goal::Full, 20_000),
+            _ => (Goal::Top, 200_000),
+        };
+        let limit = std::env::args()
+            .nth(4)
+            .map_or(default_limit, |n| n.parse::<usize>().unwrap());
+        for crane in [&CrateMover9000 as &dyn Crane, &CrateMover9001] {
+            let (history, _) =
+                apply_moves(crane, crate_stack.clone(), &moves).unwrap_or_else(|e| panic!("{}", e));
+            let best = optimize(crane, &crate_stack, history.current(), goal, limit);
+            dbg!(moves.len(), best.as_ref().map(|b| b.len()), best);
+        }
+        return;
+    }
+
+    if let Some(name) = std::env::args().nth(2) {
+        let crane = parse_crane(&name).unwrap_or_else(|| panic!("unknown crane: {}", name));
+        let (mut history, logs) =
+            apply_moves(crane.as_ref(), crate_stack, &moves).unwrap_or_else(|e| panic!("{}", e));
+        for (i, log) in logs.iter().enumerate() {
+            let tops = answer(history.after(i + 1).unwrap());
+            println!("{:?}: {:?} -> {}", log.mv, log.lifts, tops);
+        }
+        // Optionally rewind to show the stacks after a given number of moves.
+        if let Some(n) = std::env::args().nth(3) {
+            let n = n.parse::<usize>().unwrap();
+            while history.current > n && history.undo() {}
+            println!("{}", history.current());
+            while history.redo() {}
+        }
+        dbg!(answer(history.current()));
+        return;
+    }
+
+    for crane in [&CrateMover9000 as &dyn Crane, &CrateMover9001] {
+        let (history, _) =
+            apply_moves(crane, crate_stack.clone(), &moves).unwrap_or_else(|e| panic!("{}", e));
+        dbg!(answer(history.current()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// Stacks labeled 1, 2, 3...
+    fn numbered(stacks: Vec<Vec<char>>) -> CrateStack {
+        CrateStack::new((1..=stacks.len()).collect(), stacks).unwrap()
+    }
+
+    #[test]
+    fn test_validation() {
+        let crate_stack = numbered(vec![vec!['A', 'B'], vec![]]);
+        assert_eq!(
+            parse_moves("move 1 from 1 to 2\nmove 1 to 2", 7),
+            Err(MoveError::Malformed {
+                line: 8,
+                text: "move 1 to 2".to_string()
+            })
+        );
+        let mut history = History::new(crate_stack);
+        assert_eq!(
+            history
+                .apply(
+                    &CrateMover9001,
+                    3,
+                    Move {
+                        count: 1,
+                        from: 1,
+                        to: 3
+                    }
+                )
+                .unwrap_err(),
+            MoveError::NoSuchStack { line: 3, stack: 3 }
+        );
+        assert_eq!(
+            history
+                .apply(
+                    &CrateMover9001,
+                    4,
+                    Move {
+                        count: 3,
+                        from: 1,
+                        to: 2
+                    }
+                )
+                .unwrap_err(),
+            MoveError::NotEnoughCrates {
+                line: 4,
+                stack: 1,
+                have: 2,
+                want: 3
+            }
+        );
+        // Failed moves leave no trace.
+        assert_eq!(history.states.len(), 1);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::new(numbered(vec![vec!['A', 'B'], vec![]]));
+        let mv = Move {
+            count: 1,
+            from: 1,
+            to: 2,
+        };
+        history.apply(&CrateMover9000, 1, mv).unwrap();
+        history.apply(&CrateMover9000, 2, mv).unwrap();
+        assert_eq!(answer(history.current()), " A");
+        assert!(history.undo());
+        assert_eq!(answer(history.current()), "AB");
+        assert!(history.redo());
+        assert!(!history.redo());
+        assert!(history.undo() && history.undo() && !history.undo());
+        assert_eq!(
+            answer(&numbered(vec![vec!['A'], vec![], vec!['B', 'C']])),
+            "A C"
+        );
+        assert_eq!(
+            history.after(1),
+            Some(&numbered(vec![vec!['A'], vec!['B']]))
+        );
+        // A new move after undoing drops the redo states.
+        history
+            .apply(
+                &CrateMover9000,
+                1,
+                Move {
+                    count: 2,
+                    from: 1,
+                    to: 2,
+                },
+            )
+            .unwrap();
+        assert!(!history.redo());
+        assert_eq!(history.after(2), None);
+        assert_eq!(history.current(), &numbered(vec![vec![], vec!['B', 'A']]));
+    }
 
-    let mut part1_stack = crate_stack.clone();
-    parse_and_apply_moves_part1(&mut part1_stack, moves);
-    dbg!(answer(&part1_stack));
+    #[test]
+    fn test_cranes() {
+        let mv = Move {
+            count: 3,
+            from: 1,
+            to: 2,
+        };
+        let mut crate_stack = numbered(vec![vec!['A', 'B', 'C'], vec!['D']]);
+        let log = CapacityCrane::new(2).unwrap().apply(&mut crate_stack, mv);
+        assert_eq!(log.lifts, vec![vec!['B', 'C'], vec!['A']]);
+        assert_eq!(
+            crate_stack,
+            numbered(vec![vec![], vec!['D', 'B', 'C', 'A']])
+        );
+        let mut shifted = numbered(vec![vec!['A', 'B', 'C'], vec!['D']]);
+        CapacityCrane::new(2).unwrap().shift(&mut shifted, mv);
+        assert_eq!(shifted, crate_stack);
+
+        for (name, lifts) in [
+            ("9000", vec![1, 1, 1]),
+            ("9001", vec![3]),
+            ("capacity=2", vec![2, 1]),
+            ("capacity=5", vec![3]),
+        ] {
+            let crane = parse_crane(name).unwrap();
+            let mut crate_stack = numbered(vec![vec!['A', 'B', 'C'], vec![]]);
+            let log = crane.apply(&mut crate_stack, mv);
+            assert_eq!(log.lifts.iter().map(|l| l.len()).collect::<Vec<_>>(), lifts);
+        }
+        for name in ["capacity=0", "capacity=", "capacity=-1", "9002", ""] {
+            assert!(parse_crane(name).is_none(), "{}", name);
+        }
+        assert!(CapacityCrane::new(0).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "crane lifted 0 of 1 crates")]
+    fn test_stuck_crane() {
+        struct Stuck;
+        impl Crane for Stuck {
+            fn lift_size(&self, _remaining: usize) -> usize {
+                0
+            }
+        }
+        let mut crate_stack = numbered(vec![vec!['A'], vec![]]);
+        Stuck.apply(
+            &mut crate_stack,
+            Move {
+                count: 1,
+                from: 1,
+                to: 2,
+            },
+        );
+    }
+
+    const SAMPLE: &str = "    [D]    \n[N] [C]    \n[Z] [M] [P]\n 1   2   3 ";
+
+    #[test]
+    fn test_parse_and_render() {
+        let crate_stack = CrateStack::parse(SAMPLE).unwrap();
+        assert_eq!(
+            crate_stack,
+            numbered(vec![vec!['Z', 'N'], vec!['M', 'C', 'D'], vec!['P']])
+        );
+        assert_eq!(crate_stack.to_string(), SAMPLE);
+
+        // Labels come from the footer, and trailing whitespace may be trimmed.
+        let crate_stack = CrateStack::parse("[A]\n[B]     [C]\n 7  12  100").unwrap();
+        assert_eq!(crate_stack.ids, vec![7, 12, 100]);
+        assert_eq!(crate_stack.stacks, vec![vec!['B', 'A'], vec![], vec!['C']]);
+
+        assert_eq!(CrateStack::parse(""), Err(DiagramError::Empty));
+        assert_eq!(
+            CrateStack::parse("[A]\n 1   1 "),
+            Err(DiagramError::DuplicateLabel(1))
+        );
+        assert_eq!(
+            CrateStack::parse("(A)\n 1 "),
+            Err(DiagramError::BadCrate { line: 1, column: 0 })
+        );
+        assert_eq!(
+            CrateStack::parse("    [A]\n[B]    \n 1   2 "),
+            Err(DiagramError::Floating { line: 1, column: 1 })
+        );
+        for text in ["[A] [é]\n 1   2 ", "[A] []]\n 1   2 ", "[A] [\t]\n 1   2 "] {
+            assert_eq!(
+                CrateStack::parse(text),
+                Err(DiagramError::BadCrate { line: 1, column: 1 })
+            );
+        }
+        assert_eq!(
+            CrateStack::parse(" 1   x "),
+            Err(DiagramError::BadLabel {
+                column: 1,
+                text: " x ".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_optimize() {
+        let initial = numbered(vec![vec!['A', 'B'], vec![], vec![]]);
+        let target = numbered(vec![vec![], vec!['B', 'A'], vec![]]);
+        // One crate at a time reverses the order for free...
+        let moves = optimize(&CrateMover9000, &initial, &target, Goal::Full, 1000).unwrap();
+        assert_eq!(
+            moves,
+            vec![Move {
+                count: 2,
+                from: 1,
+                to: 2
+            }]
+        );
+        // ...while lifting both at once needs a detour.
+        let moves = optimize(&CrateMover9001, &initial, &target, Goal::Full, 1000).unwrap();
+        assert_eq!(moves.len(), 2);
+        let (history, _) = apply_moves(
+            &CrateMover9001,
+            initial.clone(),
+            &moves.into_iter().map(|mv| (0, mv)).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        assert_eq!(history.current(), &target);
+
+        // The sample's four moves collapse into fewer.
+        let initial = CrateStack::parse(SAMPLE).unwrap();
+        let moves = parse_moves(
+            "move 1 from 2 to 1\nmove 3 from 1 to 3\nmove 2 from 2 to 1\nmove 1 from 1 to 2",
+            1,
+        )
+        .unwrap();
+        let (history, _) = apply_moves(&CrateMover9000, initial.clone(), &moves).unwrap();
+        let best = optimize(
+            &CrateMover9000,
+            &initial,
+            history.current(),
+            Goal::Top,
+            100_000,
+        );
+        assert_eq!(best.unwrap().len(), 2);
+        assert!(optimize(&CrateMover9000, &initial, history.current(), Goal::Full, 0).is_none());
+    }
 
-    let mut part2_stack = crate_stack.clone();
-    parse_and_apply_moves_part2(&mut part2_stack, moves);
-    dbg!(answer(&part2_stack));
+    /// Fewest moves to reach `goal`, trying every move breadth first.
+    fn brute_force(
+        crane: &dyn Crane,
+        initial: &CrateStack,
+        target: &CrateStack,
+        goal: Goal,
+    ) -> Option<usize> {
+        let mut seen = HashSet::from([initial.clone()]);
+        let mut frontier = vec![initial.clone()];
+        let mut steps = 0;
+        while !frontier.is_empty() {
+            if frontier.iter().any(|c| goal.reached(c, target)) {
+                return Some(steps);
+            }
+            let mut next_frontier = Vec::new();
+            for crate_stack in &frontier {
+                for &from in &crate_stack.ids {
+                    for &to in &crate_stack.ids {
+                        for count in 1..=crate_stack.get(from).unwrap().len() {
+                            if from == to {
+                                continue;
+                            }
+                            let mut next = crate_stack.clone();
+                            crane.apply(&mut next, Move { count, from, to });
+                            if seen.insert(next.clone()) {
+                                next_frontier.push(next);
+                            }
+                        }
+                    }
+                }
+            }
+            frontier = next_frontier;
+            steps += 1;
+        }
+        None
+    }
+
+    #[test]
+    fn test_optimize_matches_brute_force() {
+        let check = |crane: &dyn Crane, initial: &CrateStack, target: &CrateStack, goal| {
+            let moves = optimize(crane, initial, target, goal, usize::MAX);
+            let expected = brute_force(crane, initial, target, goal);
+            assert_eq!(moves.as_ref().map(|m| m.len()), expected);
+            if let Some(moves) = moves {
+                let mut crate_stack = initial.clone();
+                for mv in moves {
+                    crane.apply(&mut crate_stack, mv);
+                }
+                assert!(goal.reached(&crate_stack, target));
+            }
+        };
+        // D has to come off the first stack without ending up on top of the second.
+        let initial = numbered(vec![vec!['B', 'D'], vec!['A', 'C', 'E', 'F']]);
+        let target = numbered(vec![vec!['B'], vec!['A', 'C', 'D', 'E', 'F']]);
+        for goal in [Goal::Top, Goal::Full] {
+            check(&CrateMover9001, &initial, &target, goal);
+        }
+
+        let mut rng = lib::Rng(0x853c49e6748fea9b);
+        let mut random = |n: usize| rng.below(n as u64) as usize;
+        for _ in 0..300 {
+            // Few kinds of crates, so that tops can be matched in more than one way.
+            let mut crates: Vec<char> = (0..random(6))
+                .map(|_| (b'A' + random(3) as u8) as char)
+                .collect();
+            let count = 2 + random(3);
+            let mut initial = numbered(vec![vec![]; count]);
+            for &c in &crates {
+                initial.stacks[random(count)].push(c);
+            }
+            for i in (1..crates.len()).rev() {
+                crates.swap(i, random(i + 1));
+            }
+            let mut target = numbered(vec![vec![]; count]);
+            for &c in &crates {
+                target.stacks[random(count)].push(c);
+            }
+            for crane in [&CrateMover9000 as &dyn Crane, &CrateMover9001] {
+                for goal in [Goal::Top, Goal::Full] {
+                    check(crane, &initial, &target, goal);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_optimize_input() {
+        let input = std::fs::read_to_string("input/day5.txt").expect("read failed");
+        let (initial, moves) = input.split_once("\n\n").unwrap();
+        let moves = parse_moves(moves, initial.lines().count() + 2).unwrap();
+        let initial = CrateStack::parse(initial).unwrap();
+        let (history, _) = apply_moves(&CrateMover9000, initial.clone(), &moves).unwrap();
+        let target = history.current();
+
+        // The estimate never overshoots, so no plan is shorter.
+        let best = optimize(&CrateMover9000, &initial, target, Goal::Top, 20_000).unwrap();
+        assert_eq!(best.len(), 8);
+        let (history, _) = apply_moves(
+            &CrateMover9000,
+            initial,
+            &best.into_iter().map(|mv| (0, mv)).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        assert_eq!(answer(history.current()), answer(target));
+    }
+
+    #[test]
+    fn test_render_round_trip() {
+        let mut rng = lib::Rng(0x2545f4914f6cdd1d);
+        let mut random = |n: usize| rng.below(n as u64) as usize;
+        for _ in 0..500 {
+            let mut ids = Vec::new();
+            for _ in 0..1 + random(12) {
+                // Now and then a label too wide for its slot.
+                let id = random(1100);
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+            let stacks: Vec<Vec<char>> = ids
+                .iter()
+                .map(|_| {
+                    (0..random(8))
+                        .map(|_| match random(40) {
+                            // Now and then a crate that can't be drawn, or nearly can't.
+                            0 => ['é', ']', ' ', '\t', '\u{7f}'][random(5)],
+                            1 => ['[', '~', '!', '0'][random(4)],
+                            _ => (b'A' + random(26) as u8) as char,
+                        })
+                        .collect()
+                })
+                .collect();
+            let wide = ids.iter().find(|id| **id >= 1000).cloned();
+            let invalid = stacks
+                .iter()
+                .flatten()
+                .find(|c| "é] \t\u{7f}".contains(**c))
+                .cloned();
+            let expected = match (wide, invalid) {
+                (Some(id), _) => Some(DiagramError::WideLabel(id)),
+                (None, Some(c)) => Some(DiagramError::InvalidCrate(c)),
+                (None, None) => None,
+            };
+            match CrateStack::new(ids, stacks) {
+                Ok(crate_stack) => {
+                    assert_eq!(expected, None);
+                    assert_eq!(CrateStack::parse(&crate_stack.to_string()), Ok(crate_stack))
+                }
+                Err(e) => assert_eq!(Some(e), expected),
+            }
+        }
+        // Neither of these has a diagram to round trip through.
+        assert_eq!(
+            CrateStack::new(vec![7, 1000], vec![vec![], vec!['A']]),
+            Err(DiagramError::WideLabel(1000))
+        );
+        assert_eq!(CrateStack::new(vec![], vec![]), Err(DiagramError::Empty));
+        assert_eq!(
+            CrateStack::new(vec![1], vec![vec!['é']]),
+            Err(DiagramError::InvalidCrate('é'))
+        );
+    }
 }
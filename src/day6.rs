@@ -1,22 +1,386 @@
-use std::collections::HashSet;
+use std::{
+    fmt,
+    io::{self, BufReader, Read},
+    ops::Range,
+};
 
 mod lib;
 
-fn find_first_unique_sequence(input: &str, window: usize) -> usize {
-    let chars: Vec<_> = input.chars().collect();
-    for i in window..chars.len() {
-        let x = &chars[i - window..i];
-        let h = HashSet::<char>::from_iter(x.iter().cloned());
-        if h.len() == window {
-            return i;
+/// Rolling window over a datastream that knows how many distinct bytes it holds.
+struct MarkerDetector {
+    window: usize,
+    recent: Vec<u8>,
+    counts: [usize; 256],
+    distinct: usize,
+    /// Number of bytes pushed so far.
+    pos: usize,
+}
+
+impl MarkerDetector {
+    fn new(window: usize) -> MarkerDetector {
+        assert!(window > 0, "window must not be empty");
+        MarkerDetector {
+            window,
+            recent: vec![0; window],
+            counts: [0; 256],
+            distinct: 0,
+            pos: 0,
+        }
+    }
+
+    /// Adds a byte to the window, and returns whether the last `window` bytes are all different.
+    fn push(&mut self, byte: u8) -> bool {
+        let slot = self.pos % self.window;
+        if self.pos >= self.window {
+            let old = self.recent[slot] as usize;
+            self.counts[old] -= 1;
+            if self.counts[old] == 0 {
+                self.distinct -= 1;
+            }
+        }
+        self.recent[slot] = byte;
+        self.counts[byte as usize] += 1;
+        if self.counts[byte as usize] == 1 {
+            self.distinct += 1;
+        }
+        self.pos += 1;
+        self.distinct == self.window
+    }
+}
+
+/// Streams `reader` and calls `on_marker` with the number of bytes read at the end of every
+/// window of `window` distinct bytes. Line breaks are not part of the datastream.
+fn scan_markers(
+    reader: impl Read,
+    window: usize,
+    mut on_marker: impl FnMut(usize) -> bool,
+) -> io::Result<()> {
+    let mut detector = MarkerDetector::new(window);
+    for byte in BufReader::new(reader).bytes() {
+        let byte = byte?;
+        if byte == b'\n' || byte == b'\r' {
+            continue;
+        }
+        if detector.push(byte) && !on_marker(detector.pos) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Position right after the first marker, if there is one.
+fn find_first_marker(reader: impl Read, window: usize) -> io::Result<Option<usize>> {
+    let mut first = None;
+    scan_markers(reader, window, |pos| {
+        first = Some(pos);
+        false
+    })?;
+    Ok(first)
+}
+
+/// Positions right after every marker. Markers may overlap.
+fn find_all_markers(reader: impl Read, window: usize) -> io::Result<Vec<usize>> {
+    let mut all = Vec::new();
+    scan_markers(reader, window, |pos| {
+        all.push(pos);
+        true
+    })?;
+    Ok(all)
+}
+
+/// Describes the marker kinds in a datastream, one per line: `<name> <window> [once]`. A marker
+/// is `window` distinct bytes; `once` markers are only looked for until they are first seen.
+/// Blank lines and lines starting with `#` are ignored.
+const DEFAULT_PROTOCOL: &str = "\
+# name window [once]
+packet 4 once
+message 14 once
+";
+
+#[derive(Debug, PartialEq, Eq)]
+struct MarkerRule {
+    name: String,
+    window: usize,
+    once: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ProtocolError {
+    Malformed { line: usize, text: String },
+    DuplicateName { line: usize, name: String },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed { line, text } => {
+                write!(f, "line {}: malformed marker rule {:?}", line, text)
+            }
+            ProtocolError::DuplicateName { line, name } => {
+                write!(f, "line {}: marker {:?} defined twice", line, name)
+            }
+        }
+    }
+}
+
+fn parse_protocol(text: &str) -> Result<Vec<MarkerRule>, ProtocolError> {
+    let mut rules: Vec<MarkerRule> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let malformed = || ProtocolError::Malformed {
+            line: line_no,
+            text: line.to_string(),
+        };
+        let items: Vec<&str> = trimmed.split_whitespace().collect();
+        let (name, window, once) = match items[..] {
+            [name, window] => (name, window, false),
+            [name, window, "once"] => (name, window, true),
+            _ => return Err(malformed()),
+        };
+        let window = match window.parse::<usize>() {
+            Ok(w) if w > 0 => w,
+            _ => return Err(malformed()),
+        };
+        if rules.iter().any(|r| r.name == name) {
+            return Err(ProtocolError::DuplicateName {
+                line: line_no,
+                name: name.to_string(),
+            });
         }
+        rules.push(MarkerRule {
+            name: name.to_string(),
+            window,
+            once,
+        });
     }
-    panic!("shouldn't get here");
+    Ok(rules)
+}
+
+/// A marker and the payload that follows it, up to where the next marker starts. The bytes
+/// before the first marker come as a frame with no kind. Frames don't overlap: together their
+/// markers and payloads cover the stream once, in order.
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    kind: Option<String>,
+    marker: Range<usize>,
+    payload: Vec<u8>,
+}
+
+/// Splits a datastream into frames. Every rule watches the stream on its own and starts over
+/// after each of its markers, so markers of one kind never overlap. A marker that reaches back
+/// into the previous frame's marker is cut short to start where that one ends. When several
+/// markers end on the same byte, each gets a frame in the order the rules are listed, and all but
+/// the first are left with an empty marker.
+fn decode(reader: impl Read, rules: &[MarkerRule]) -> io::Result<Vec<Frame>> {
+    let mut detectors: Vec<Option<MarkerDetector>> = rules
+        .iter()
+        .map(|rule| Some(MarkerDetector::new(rule.window)))
+        .collect();
+    let mut frames = Vec::new();
+    let mut current = Frame {
+        kind: None,
+        marker: 0..0,
+        payload: Vec::new(),
+    };
+    let mut pos = 0;
+    for byte in BufReader::new(reader).bytes() {
+        let byte = byte?;
+        if byte == b'\n' || byte == b'\r' {
+            continue;
+        }
+        pos += 1;
+        current.payload.push(byte);
+
+        let mut fired = Vec::new();
+        for (rule, detector) in rules.iter().zip(detectors.iter_mut()) {
+            let Some(d) = detector else { continue };
+            if !d.push(byte) {
+                continue;
+            }
+            *detector = if rule.once {
+                None
+            } else {
+                Some(MarkerDetector::new(rule.window))
+            };
+            fired.push(rule);
+        }
+        for rule in fired {
+            // The current payload starts where its marker ends and stops where this one starts.
+            let start = (pos - rule.window).max(current.marker.end);
+            current.payload.truncate(start - current.marker.end);
+            let next = Frame {
+                kind: Some(rule.name.clone()),
+                marker: start..pos,
+                payload: Vec::new(),
+            };
+            frames.push(std::mem::replace(&mut current, next));
+        }
+    }
+    frames.push(current);
+    Ok(frames)
 }
 
 fn main() {
     let input = lib::read_input();
 
-    dbg!(find_first_unique_sequence(&input, 4));
-    dbg!(find_first_unique_sequence(&input, 14));
+    dbg!(find_first_marker(input.as_bytes(), 4).unwrap());
+    dbg!(find_first_marker(input.as_bytes(), 14).unwrap());
+
+    if std::env::args().nth(2).as_deref() == Some("decode") {
+        let protocol = std::env::args()
+            .nth(3)
+            .map(|path| std::fs::read_to_string(path).expect("read failed"))
+            .unwrap_or_else(|| DEFAULT_PROTOCOL.to_string());
+        let rules = parse_protocol(&protocol).unwrap_or_else(|e| panic!("{}", e));
+        for frame in decode(input.as_bytes(), &rules).unwrap() {
+            println!(
+                "{:?} marker at {:?}, {} byte payload: {}",
+                frame.kind,
+                frame.marker,
+                frame.payload.len(),
+                String::from_utf8_lossy(&frame.payload)
+            );
+        }
+    } else if let Some(window) = std::env::args().nth(2) {
+        let window = window.parse::<usize>().unwrap();
+        dbg!(find_all_markers(input.as_bytes(), window).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_first_marker() {
+        for (input, packet, message) in [
+            ("mjqjpqmgbljsphdztnvjfqwrcgsmlb", 7, 19),
+            ("bvwbjplbgvbhsrlpgdmjqwftvncz", 5, 23),
+            ("nppdvjthqldpwncqszvftbrmjlhg", 6, 23),
+            ("nznrnfrfntjfmvfwmzdfjlvtqnbhcprsg", 10, 29),
+            ("zcfzfwzzqfrljwzlrfnpqdbhtmscgvjw", 11, 26),
+        ] {
+            assert_eq!(
+                find_first_marker(input.as_bytes(), 4).unwrap(),
+                Some(packet)
+            );
+            assert_eq!(
+                find_first_marker(input.as_bytes(), 14).unwrap(),
+                Some(message)
+            );
+        }
+        assert_eq!(find_first_marker("aabbaab".as_bytes(), 3).unwrap(), None);
+        assert_eq!(find_first_marker("".as_bytes(), 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_all_markers() {
+        assert_eq!(
+            find_all_markers("abcab\nbaaa".as_bytes(), 3).unwrap(),
+            vec![3, 4, 5]
+        );
+        assert_eq!(
+            find_all_markers("aab".as_bytes(), 1).unwrap(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_parse_protocol() {
+        let rules = parse_protocol(DEFAULT_PROTOCOL).unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                MarkerRule {
+                    name: "packet".to_string(),
+                    window: 4,
+                    once: true
+                },
+                MarkerRule {
+                    name: "message".to_string(),
+                    window: 14,
+                    once: true
+                },
+            ]
+        );
+        assert_eq!(
+            parse_protocol("a 3\n\nb 0"),
+            Err(ProtocolError::Malformed {
+                line: 3,
+                text: "b 0".to_string()
+            })
+        );
+        assert_eq!(
+            parse_protocol("a 3\na 4 once"),
+            Err(ProtocolError::DuplicateName {
+                line: 2,
+                name: "a".to_string()
+            })
+        );
+    }
+
+    /// Decodes `input` and checks that the frames cover it exactly once.
+    fn decode_summary(
+        input: &str,
+        rules: &[MarkerRule],
+    ) -> Vec<(Option<String>, Range<usize>, String)> {
+        let frames = decode(input.as_bytes(), rules).unwrap();
+        let mut covered = String::new();
+        for frame in &frames {
+            assert_eq!(frame.marker.start, covered.len());
+            covered += &input[frame.marker.clone()];
+            covered += std::str::from_utf8(&frame.payload).unwrap();
+        }
+        assert_eq!(covered, input);
+        frames
+            .into_iter()
+            .map(|f| {
+                let payload = String::from_utf8(f.payload).unwrap();
+                (f.kind, f.marker, payload)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_decode() {
+        let frame = |kind: Option<&str>, marker: Range<usize>, payload: &str| {
+            (kind.map(str::to_string), marker, payload.to_string())
+        };
+        let rules = parse_protocol(DEFAULT_PROTOCOL).unwrap();
+        // The message marker reaches back into the packet marker, and starts after it instead.
+        assert_eq!(
+            decode_summary("mjqjpqmgbljsphdztnvjfqwrcgsmlb", &rules),
+            vec![
+                frame(None, 0..0, "mjq"),
+                frame(Some("packet"), 3..7, ""),
+                frame(Some("message"), 7..19, "jfqwrcgsmlb"),
+            ]
+        );
+
+        // Repeating markers split the stream into runs.
+        let rules = parse_protocol("sep 2").unwrap();
+        assert_eq!(
+            decode_summary("aabbcc", &rules),
+            vec![
+                frame(None, 0..0, "a"),
+                frame(Some("sep"), 1..3, ""),
+                frame(Some("sep"), 3..5, "c"),
+            ]
+        );
+
+        // Markers ending on the same byte each get a frame.
+        let rules = parse_protocol("a 3 once\nb 3 once").unwrap();
+        assert_eq!(
+            decode_summary("abcd", &rules),
+            vec![
+                frame(None, 0..0, ""),
+                frame(Some("a"), 0..3, ""),
+                frame(Some("b"), 3..3, "d"),
+            ]
+        );
+    }
 }
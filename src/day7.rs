@@ -1,131 +1,962 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt, fs, io,
+    path::Path,
+};
 
 mod lib;
 
+/// Index of a directory in `FileSystem::dirs`.
+type DirId = usize;
+
+const ROOT: DirId = 0;
+
 #[derive(Debug, Default)]
 struct Dir {
-    dirs: HashMap<String, Rc<RefCell<Dir>>>,
-    files: HashMap<String, usize>,
-    /// Size of files in this directory + total sizes of all subdirectories. None if it hasn't been
-    /// calculated yet.
-    total_size: Option<usize>,
-}
-
-impl Dir {
-    fn parse_ls<'a>(&mut self, lines: impl Iterator<Item = &'a str>) {
-        for line in lines {
-            dbg!(&line);
-            match line.split_once(" ").unwrap() {
-                ("dir", _) => {
-                    // we don't actually need this, as we create the dirs when we "cd" into them.
+    name: String,
+    /// None for the root.
+    parent: Option<DirId>,
+    dirs: BTreeMap<String, DirId>,
+    files: BTreeMap<String, usize>,
+    /// Size of files in this directory + total sizes of all subdirectories. Updated whenever a
+    /// file is added anywhere below.
+    total_size: usize,
+    /// Whether `ls` has been run here, i.e. whether `dirs` and `files` are complete.
+    listed: bool,
+}
+
+#[derive(Debug)]
+struct FileSystem {
+    /// All directories, with the root at `ROOT`.
+    dirs: Vec<Dir>,
+}
+
+impl FileSystem {
+    fn new() -> FileSystem {
+        FileSystem {
+            dirs: vec![Dir::default()],
+        }
+    }
+
+    /// Returns the subdirectory `name` of `parent`, creating it if needed.
+    fn mkdir(&mut self, parent: DirId, name: &str) -> DirId {
+        if let Some(id) = self.dirs[parent].dirs.get(name) {
+            return *id;
+        }
+        let id = self.dirs.len();
+        self.dirs.push(Dir {
+            name: name.to_string(),
+            parent: Some(parent),
+            ..Default::default()
+        });
+        self.dirs[parent].dirs.insert(name.to_string(), id);
+        id
+    }
+
+    /// Adds (or resizes) a file, and updates the total sizes of `dir` and all its parents.
+    fn add_file(&mut self, dir: DirId, name: &str, size: usize) {
+        let old_size = self.dirs[dir].files.insert(name.to_string(), size);
+        let old_size = old_size.unwrap_or(0);
+        let mut cur = Some(dir);
+        while let Some(id) = cur {
+            let d = &mut self.dirs[id];
+            d.total_size = d.total_size - old_size + size;
+            cur = d.parent;
+        }
+    }
+
+    /// Absolute path of a directory, e.g. "/a/b".
+    fn path(&self, id: DirId) -> String {
+        let mut names = Vec::new();
+        let mut cur = id;
+        while let Some(parent) = self.dirs[cur].parent {
+            names.push(self.dirs[cur].name.as_str());
+            cur = parent;
+        }
+        names.reverse();
+        format!("/{}", names.join("/"))
+    }
+
+    /// Follows an absolute or relative path from `from`. None if some directory along the way
+    /// doesn't exist, or the path goes above the root.
+    fn resolve(&self, from: DirId, path: &str) -> Option<DirId> {
+        let mut cur = if path.starts_with('/') { ROOT } else { from };
+        for component in path.split('/') {
+            cur = match component {
+                "" | "." => cur,
+                ".." => self.dirs[cur].parent?,
+                name => *self.dirs[cur].dirs.get(name)?,
+            };
+        }
+        Some(cur)
+    }
+
+    /// All directory ids, parents before children and siblings by name.
+    fn preorder(&self) -> Vec<DirId> {
+        let mut out = Vec::with_capacity(self.dirs.len());
+        let mut stack = vec![ROOT];
+        while let Some(id) = stack.pop() {
+            out.push(id);
+            stack.extend(self.dirs[id].dirs.values().rev());
+        }
+        out
+    }
+
+    /// All directories with their absolute paths, parents before children.
+    fn iter_dirs(&self) -> impl Iterator<Item = (String, &Dir)> + '_ {
+        self.preorder()
+            .into_iter()
+            .map(|id| (self.path(id), &self.dirs[id]))
+    }
+
+    /// Finds the sum of all total_sizes that is below 100K.
+    fn part1(&self) -> usize {
+        self.dirs
+            .iter()
+            .map(|dir| dir.total_size)
+            .filter(|size| *size <= 100_000)
+            .sum()
+    }
+
+    // - / (dir, size=48381165)
+    //   - a (dir, size=94853)
+    //     - e (dir, size=584)
+    //       - i (file, size=584)
+    //     - f (file, size=29116)
+    fn tree(&self) -> String {
+        fn walk(fs: &FileSystem, id: DirId, name: &str, depth: usize, out: &mut String) {
+            let dir = &fs.dirs[id];
+            let indent = "  ".repeat(depth);
+            *out += &format!("{}- {} (dir, size={})\n", indent, name, dir.total_size);
+            let mut entries: Vec<(&String, Result<DirId, usize>)> = dir
+                .dirs
+                .iter()
+                .map(|(name, id)| (name, Ok(*id)))
+                .chain(dir.files.iter().map(|(name, size)| (name, Err(*size))))
+                .collect();
+            entries.sort();
+            for (name, entry) in entries {
+                match entry {
+                    Ok(id) => walk(fs, id, name, depth + 1, out),
+                    Err(size) => *out += &format!("{}  - {} (file, size={})\n", indent, name, size),
                 }
-                (size, filename) => {
-                    self.files
-                        .insert(filename.to_string(), size.parse::<usize>().unwrap());
+            }
+        }
+        let mut out = String::new();
+        walk(self, ROOT, "/", 0, &mut out);
+        out
+    }
+
+    /// Lists all directories like `du -h | sort -rh` would, largest first.
+    fn du(&self) -> String {
+        let mut dirs: Vec<(String, &Dir)> = self.iter_dirs().collect();
+        dirs.sort_by(|(a_path, a), (b_path, b)| {
+            b.total_size.cmp(&a.total_size).then(a_path.cmp(b_path))
+        });
+        dirs.iter()
+            .map(|(path, dir)| format!("{}\t{}\n", human_size(dir.total_size), path))
+            .collect()
+    }
+
+    /// How much has to be deleted to have `required_free` free on a disk of `disk_size`.
+    fn must_free(&self, disk_size: usize, required_free: usize) -> usize {
+        (self.dirs[ROOT].total_size + required_free).saturating_sub(disk_size)
+    }
+
+    /// Smallest directory whose deletion frees at least `must_free`.
+    fn smallest_deletion(&self, must_free: usize) -> Option<DirId> {
+        (0..self.dirs.len())
+            .filter(|id| self.dirs[*id].total_size >= must_free)
+            .min_by_key(|id| self.dirs[*id].total_size)
+    }
+
+    /// Finds directories, none inside another, that together free at least `must_free` with as
+    /// little deleted as possible.
+    ///
+    /// Walks the directories in preorder, where deleting a directory skips over its subtree.
+    /// Every sum below `must_free` is tracked in a bitset. Since not deleting a directory always
+    /// leads to the next one in preorder, the sums reachable at each step only ever grow, so
+    /// remembering where each sum first showed up is enough to recover the choices.
+    fn cheapest_deletion_set(&self, must_free: usize) -> Option<Vec<DirId>> {
+        if must_free == 0 {
+            return Some(Vec::new());
+        }
+        if must_free > self.dirs[ROOT].total_size {
+            return None;
+        }
+        let order = self.preorder();
+        let n = order.len();
+        let size = |i: usize| self.dirs[order[i]].total_size;
+        // skip[i] is where preorder continues after the subtree at i.
+        let mut skip: Vec<usize> = (1..=n).collect();
+        for i in (0..n).rev() {
+            skip[i] = (0..self.dirs[order[i]].dirs.len()).fold(i + 1, |j, _| skip[j]);
+        }
+
+        let words = must_free.div_ceil(64);
+        let mut reachable = vec![0u64; words];
+        reachable[0] = 1;
+        let mut first = vec![u32::MAX; must_free];
+        first[0] = 0;
+        // Sums that become reachable once preorder gets to a given position.
+        let mut pending: HashMap<usize, Vec<u64>> = HashMap::new();
+        // (total, position deleted last, sum before it)
+        let mut best: Option<(usize, usize, usize)> = None;
+
+        for (i, &next) in skip.iter().enumerate() {
+            if let Some(incoming) = pending.remove(&i) {
+                for (w, (cur, new)) in reachable.iter_mut().zip(incoming).enumerate() {
+                    let mut added = new & !*cur;
+                    *cur |= new;
+                    while added != 0 {
+                        first[w * 64 + added.trailing_zeros() as usize] = i as u32;
+                        added &= added - 1;
+                    }
                 }
             }
+            let size = size(i);
+            // Deleting directory i finishes the plan if it's big enough on top of what we have.
+            if let Some(sum) = next_set_bit(&reachable, must_free.saturating_sub(size)) {
+                if best.is_none_or(|(total, _, _)| sum + size < total) {
+                    best = Some((sum + size, i, sum));
+                }
+            }
+            // Otherwise, carry the sums that stay below must_free over to skip[i].
+            if size < must_free && next < n {
+                let target = pending.entry(next).or_insert_with(|| vec![0; words]);
+                shift_or(target, &reachable, size);
+                if let Some(last) = target.last_mut() {
+                    if !must_free.is_multiple_of(64) {
+                        *last &= (1 << (must_free % 64)) - 1;
+                    }
+                }
+            }
+        }
+
+        let (_, last, mut sum) = best?;
+        let mut chosen = vec![order[last]];
+        while sum > 0 {
+            // Some directory that ends right where `sum` first became reachable got it there.
+            let at = first[sum] as usize;
+            let k = (0..at)
+                .find(|k| skip[*k] == at && size(*k) <= sum && first[sum - size(*k)] as usize <= *k)
+                .unwrap();
+            chosen.push(order[k]);
+            sum -= size(k);
         }
+        chosen.reverse();
+        Some(chosen)
     }
+}
 
-    fn recalculate_total_sizes(&mut self) {
-        let mut total_size = 0usize;
-        for dir in self.dirs.values_mut() {
-            dir.borrow_mut().recalculate_total_sizes();
-            total_size += dir.borrow().total_size.unwrap();
+/// `target |= source << shift`, on bitsets stored as little-endian words.
+fn shift_or(target: &mut [u64], source: &[u64], shift: usize) {
+    let (words, bits) = (shift / 64, shift % 64);
+    for i in (words..target.len()).rev() {
+        let j = i - words;
+        let mut v = source[j] << bits;
+        if bits != 0 && j > 0 {
+            v |= source[j - 1] >> (64 - bits);
         }
-        for file_size in self.files.values_mut() {
-            total_size += *file_size;
+        target[i] |= v;
+    }
+}
+
+/// Position of the first set bit at or after `from`.
+fn next_set_bit(bits: &[u64], from: usize) -> Option<usize> {
+    let mut w = from / 64;
+    let mut word = *bits.get(w)? & (u64::MAX << (from % 64));
+    while word == 0 {
+        w += 1;
+        word = *bits.get(w)?;
+    }
+    Some(w * 64 + word.trailing_zeros() as usize)
+}
+
+/// Formats a size like `du -h`: powers of 1024, rounded up, with one decimal below 10.
+fn human_size(size: usize) -> String {
+    let mut unit = 0;
+    let mut scale = 1usize;
+    while size > 1024 * scale - 1 && unit < 4 {
+        scale *= 1024;
+        unit += 1;
+    }
+    if unit == 0 {
+        return size.to_string();
+    }
+    let suffix = ["", "K", "M", "G", "T"][unit];
+    let tenths = (size * 10).div_ceil(scale);
+    if tenths < 100 {
+        format!("{}.{}{}", tenths / 10, tenths % 10, suffix)
+    } else {
+        format!("{}{}", size.div_ceil(scale), suffix)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ShellError {
+    UnknownCommand {
+        line: usize,
+        text: String,
+    },
+    /// Output that doesn't look like `ls` output, or comes after something other than `ls`.
+    UnexpectedOutput {
+        line: usize,
+        text: String,
+    },
+    /// `cd` into a directory that no listing has shown. Only in strict mode.
+    NoSuchDir {
+        line: usize,
+        path: String,
+    },
+    /// `cd ..` at the root. Only in strict mode.
+    AboveRoot {
+        line: usize,
+    },
+    /// A listing disagrees with what is already known about the directory.
+    Contradiction {
+        line: usize,
+        path: String,
+        name: String,
+    },
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellError::UnknownCommand { line, text } => {
+                write!(f, "line {}: unknown command {:?}", line, text)
+            }
+            ShellError::UnexpectedOutput { line, text } => {
+                write!(f, "line {}: unexpected output {:?}", line, text)
+            }
+            ShellError::NoSuchDir { line, path } => {
+                write!(f, "line {}: cd: no such directory {:?}", line, path)
+            }
+            ShellError::AboveRoot { line } => write!(f, "line {}: cd: already at /", line),
+            ShellError::Contradiction { line, path, name } => write!(
+                f,
+                "line {}: listing of {} contradicts earlier output for {:?}",
+                line, path, name
+            ),
         }
-        self.total_size = Some(total_size);
     }
+}
 
-    /// Finds the sum of all total_sizes that is below 100K.
-    fn part1(&self) -> usize {
-        let dir_100k_sizes = self
-            .dirs
-            .values()
-            .map(|dir| dir.borrow().part1())
-            .sum::<usize>();
-        let our_size = self.total_size.unwrap();
-        if our_size <= 100_000 {
-            our_size + dir_100k_sizes
+/// One directory's `ls` output.
+#[derive(Default)]
+struct Listing {
+    /// Line number of the `ls` command.
+    line: usize,
+    dirs: Vec<String>,
+    files: BTreeMap<String, usize>,
+}
+
+/// Replays a terminal transcript. In strict mode, `cd` only goes into directories some listing
+/// has shown, and never above the root; otherwise missing directories are created and `cd ..`
+/// at the root stays there.
+struct Shell {
+    fs: FileSystem,
+    cwd: DirId,
+    strict: bool,
+}
+
+impl Shell {
+    fn run(input: &str, strict: bool) -> Result<FileSystem, ShellError> {
+        let mut shell = Shell {
+            fs: FileSystem::new(),
+            cwd: ROOT,
+            strict,
+        };
+        let mut listing: Option<Listing> = None;
+        for (i, text) in input.lines().enumerate() {
+            let line = i + 1;
+            if let Some(cmd) = text.strip_prefix("$ ") {
+                if let Some(done) = listing.take() {
+                    shell.apply_listing(done)?;
+                }
+                if cmd == "ls" {
+                    listing = Some(Listing {
+                        line,
+                        ..Default::default()
+                    });
+                } else if let Some(path) = cmd.strip_prefix("cd ") {
+                    shell.cd(line, path)?;
+                } else {
+                    return Err(ShellError::UnknownCommand {
+                        line,
+                        text: text.to_string(),
+                    });
+                }
+                continue;
+            }
+            let unexpected = || ShellError::UnexpectedOutput {
+                line,
+                text: text.to_string(),
+            };
+            let listing = listing.as_mut().ok_or_else(unexpected)?;
+            match text.split_once(" ").ok_or_else(unexpected)? {
+                ("dir", name) => listing.dirs.push(name.to_string()),
+                (size, name) => {
+                    let size = size.parse::<usize>().map_err(|_| unexpected())?;
+                    listing.files.insert(name.to_string(), size);
+                }
+            }
+        }
+        if let Some(done) = listing.take() {
+            shell.apply_listing(done)?;
+        }
+        Ok(shell.fs)
+    }
+
+    fn cd(&mut self, line: usize, path: &str) -> Result<(), ShellError> {
+        if let Some(id) = self.fs.resolve(self.cwd, path) {
+            self.cwd = id;
+            return Ok(());
+        }
+        // Walk again, one component at a time, to either fill in the gaps or report them.
+        let mut cur = if path.starts_with('/') {
+            ROOT
         } else {
-            dir_100k_sizes
+            self.cwd
+        };
+        for component in path.split('/') {
+            cur = match component {
+                "" | "." => cur,
+                ".." => match self.fs.dirs[cur].parent {
+                    Some(parent) => parent,
+                    None if self.strict => return Err(ShellError::AboveRoot { line }),
+                    None => ROOT,
+                },
+                name => match self.fs.dirs[cur].dirs.get(name) {
+                    Some(id) => *id,
+                    None if self.strict => {
+                        return Err(ShellError::NoSuchDir {
+                            line,
+                            path: path.to_string(),
+                        })
+                    }
+                    None => self.fs.mkdir(cur, name),
+                },
+            };
         }
+        self.cwd = cur;
+        Ok(())
     }
 
-    /// Finds the smallest total size >= at_least.
-    fn part2(&self, at_least: usize) -> usize {
-        let mut min_dir = self
+    /// Adds a listing of the current directory. Listing a directory again is fine, as long as
+    /// it shows exactly the same thing.
+    fn apply_listing(&mut self, listing: Listing) -> Result<(), ShellError> {
+        let dir = &self.fs.dirs[self.cwd];
+        let contradiction = |name: &str| ShellError::Contradiction {
+            line: listing.line,
+            path: self.fs.path(self.cwd),
+            name: name.to_string(),
+        };
+        if let Some(name) = listing
             .dirs
-            .values()
-            .fold(usize::MAX, |acc, dir| acc.min(dir.borrow().part2(at_least)));
+            .iter()
+            .find(|name| listing.files.contains_key(*name) || dir.files.contains_key(*name))
+        {
+            return Err(contradiction(name));
+        }
+        if let Some(name) = listing
+            .files
+            .keys()
+            .find(|name| dir.dirs.contains_key(*name))
+        {
+            return Err(contradiction(name));
+        }
+        // Known subdirectories must show up, even on the first listing: `cd` may have made them.
+        if let Some(name) = dir.dirs.keys().find(|name| !listing.dirs.contains(name)) {
+            return Err(contradiction(name));
+        }
+        if dir.listed {
+            let changed = dir
+                .files
+                .iter()
+                .find(|(name, size)| listing.files.get(*name) != Some(size));
+            let added = listing
+                .files
+                .keys()
+                .find(|name| !dir.files.contains_key(*name));
+            if let Some(name) = changed.map(|(name, _)| name).or(added) {
+                return Err(contradiction(name));
+            }
+            if let Some(name) = listing
+                .dirs
+                .iter()
+                .find(|name| !dir.dirs.contains_key(*name))
+            {
+                return Err(contradiction(name));
+            }
+            return Ok(());
+        }
 
-        let our_size = self.total_size.unwrap();
-        if our_size >= at_least {
-            min_dir = min_dir.min(our_size)
+        for name in &listing.dirs {
+            self.fs.mkdir(self.cwd, name);
         }
-        min_dir
+        for (name, size) in &listing.files {
+            self.fs.add_file(self.cwd, name, *size);
+        }
+        self.fs.dirs[self.cwd].listed = true;
+        Ok(())
     }
 }
 
-fn split_into_commands(input: &str) -> Vec<&str> {
-    input.trim_start_matches("$ ").split("\n$ ").collect()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Traversal {
+    /// `cd` into each directory from its parent and back out with `cd ..`, like the puzzle.
+    DepthFirst,
+    /// Visit directories level by level, using absolute `cd` paths.
+    BreadthFirst,
 }
 
-fn simulate_commands(commands: &[&str]) -> Dir {
-    let root = Rc::new(RefCell::new(Dir::default()));
+struct TranscriptOptions {
+    traversal: Traversal,
+    /// Percent chance, after each listing, to jump to an already listed directory and list it
+    /// again.
+    revisit_percent: u64,
+    seed: u64,
+}
 
-    let mut dir_stack: Vec<Rc<RefCell<Dir>>> = Vec::new();
-    dir_stack.push(root.clone());
+impl FileSystem {
+    /// Reads a directory tree from disk. Symlinks are skipped.
+    fn from_disk(path: &Path) -> io::Result<FileSystem> {
+        fn walk(fs: &mut FileSystem, id: DirId, path: &Path) -> io::Result<()> {
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    let child = fs.mkdir(id, &name);
+                    walk(fs, child, &entry.path())?;
+                } else if file_type.is_file() {
+                    fs.add_file(id, &name, entry.metadata()?.len() as usize);
+                }
+            }
+            Ok(())
+        }
+        let mut fs = FileSystem::new();
+        walk(&mut fs, ROOT, path)?;
+        Ok(fs)
+    }
+
+    /// Writes a terminal session that explores the whole tree, in the puzzle's format.
+    fn transcript(&self, options: &TranscriptOptions) -> String {
+        let mut rng = lib::Rng(options.seed.max(1));
+        let mut out = String::from("$ cd /\n");
+        let mut listed: Vec<DirId> = Vec::new();
 
-    for cmd in commands {
-        let mut lines = cmd.lines();
-        let first_line = lines.next().unwrap();
-        match first_line {
-            "cd /" => {
-                dir_stack.clear();
-                dir_stack.push(root.clone());
+        let list = |out: &mut String, id: DirId| {
+            *out += "$ ls\n";
+            let dir = &self.dirs[id];
+            for name in dir.dirs.keys() {
+                *out += &format!("dir {}\n", name);
             }
-            "cd .." => {
-                if dir_stack.len() > 1 {
-                    dir_stack.pop();
-                }
+            for (name, size) in dir.files.iter() {
+                *out += &format!("{} {}\n", size, name);
             }
-            "ls" => {
-                dir_stack.last().unwrap().borrow_mut().parse_ls(lines);
+        };
+        let mut visit = |out: &mut String, id: DirId| {
+            list(out, id);
+            listed.push(id);
+            if rng.below(100) < options.revisit_percent {
+                let again = listed[rng.below(listed.len() as u64) as usize];
+                *out += &format!("$ cd {}\n", self.path(again));
+                list(out, again);
+                *out += &format!("$ cd {}\n", self.path(id));
             }
-            // Otherwise, it must be "cd foo".
-            _ => {
-                assert!(first_line.starts_with("cd "));
-                let path: String = first_line.chars().skip(3).collect();
-                let new_dir = {
-                    let mut cur_dir = dir_stack.last().unwrap().borrow_mut();
-                    cur_dir.dirs.entry(path).or_default().clone()
-                };
-                dir_stack.push(new_dir);
+        };
+
+        match options.traversal {
+            Traversal::DepthFirst => {
+                // (directory, whether its subdirectories have been entered yet)
+                let mut stack = vec![(ROOT, false)];
+                while let Some((id, entered)) = stack.pop() {
+                    if entered {
+                        if id != ROOT {
+                            out += "$ cd ..\n";
+                        }
+                        continue;
+                    }
+                    if id != ROOT {
+                        out += &format!("$ cd {}\n", self.dirs[id].name);
+                    }
+                    visit(&mut out, id);
+                    stack.push((id, true));
+                    stack.extend(self.dirs[id].dirs.values().rev().map(|id| (*id, false)));
+                }
+            }
+            Traversal::BreadthFirst => {
+                let mut queue = VecDeque::from([ROOT]);
+                while let Some(id) = queue.pop_front() {
+                    if id != ROOT {
+                        out += &format!("$ cd {}\n", self.path(id));
+                    }
+                    visit(&mut out, id);
+                    queue.extend(self.dirs[id].dirs.values());
+                }
             }
         }
+        out
     }
-    root.take()
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let flag = |name: &str| args.iter().any(|arg| arg == name);
+    let option = |name: &str| {
+        args.iter()
+            .find_map(|arg| arg.strip_prefix(&format!("{}=", name)))
+            .map(|value| value.parse::<u64>().unwrap())
+    };
+    if flag("generate") {
+        // The first argument is a directory to describe rather than an input file.
+        let path = std::env::args().nth(1).expect("pls provide a directory");
+        let fs = FileSystem::from_disk(Path::new(&path)).expect("read failed");
+        let options = TranscriptOptions {
+            traversal: if flag("bfs") {
+                Traversal::BreadthFirst
+            } else {
+                Traversal::DepthFirst
+            },
+            revisit_percent: option("revisit").unwrap_or(0),
+            seed: option("seed").unwrap_or(1),
+        };
+        print!("{}", fs.transcript(&options));
+        return;
+    }
+
     let input = lib::read_input();
-    let commands = split_into_commands(&input);
-    dbg!(&commands);
-    let mut root = simulate_commands(&commands);
-    dbg!(&root);
-    root.recalculate_total_sizes();
-    dbg!(&root);
-    dbg!(root.part1());
+    let fs = Shell::run(&input, flag("strict")).unwrap_or_else(|e| panic!("{}", e));
+    if flag("tree") {
+        print!("{}", fs.tree());
+    }
+    if flag("du") {
+        print!("{}", fs.du());
+    }
+    dbg!(fs.part1());
 
     // Part 2:
     const TOTAL_SPACE: usize = 70000000;
     const SPACE_NEEDED: usize = 30000000;
-    let max_allowed_used = TOTAL_SPACE - SPACE_NEEDED;
-    let must_delete_at_least = root.total_size.unwrap() - max_allowed_used;
-    dbg!(&must_delete_at_least);
+    let must_free = fs.must_free(TOTAL_SPACE, SPACE_NEEDED);
+    dbg!(&must_free);
+
+    let smallest = fs.smallest_deletion(must_free).unwrap();
+    dbg!(fs.path(smallest), fs.dirs[smallest].total_size);
+
+    if flag("plan") {
+        let plan = fs.cheapest_deletion_set(must_free).unwrap();
+        for id in plan.iter() {
+            println!("{}\t{}", fs.dirs[*id].total_size, fs.path(*id));
+        }
+        dbg!(plan.iter().map(|id| fs.dirs[*id].total_size).sum::<usize>());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_system() {
+        let mut fs = FileSystem::new();
+        let a = fs.mkdir(ROOT, "a");
+        let e = fs.mkdir(a, "e");
+        assert_eq!(fs.mkdir(ROOT, "a"), a);
+        fs.add_file(e, "i", 584);
+        fs.add_file(a, "f", 29116);
+        fs.add_file(ROOT, "b.txt", 14848514);
+        assert_eq!(fs.dirs[e].total_size, 584);
+        assert_eq!(fs.dirs[a].total_size, 29700);
+        assert_eq!(fs.dirs[ROOT].total_size, 14878214);
+        // Re-adding a file replaces its size.
+        fs.add_file(e, "i", 600);
+        assert_eq!(fs.dirs[ROOT].total_size, 14878230);
+
+        assert_eq!(fs.path(e), "/a/e");
+        assert_eq!(fs.path(ROOT), "/");
+        assert_eq!(fs.resolve(ROOT, "a/e"), Some(e));
+        assert_eq!(fs.resolve(e, "../../a/./e"), Some(e));
+        assert_eq!(fs.resolve(e, "/"), Some(ROOT));
+        assert_eq!(fs.resolve(ROOT, ".."), None);
+        assert_eq!(fs.resolve(ROOT, "x"), None);
+
+        let paths: Vec<String> = fs.iter_dirs().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["/", "/a", "/a/e"]);
+    }
+
+    #[test]
+    fn test_shell_paths() {
+        let input = "$ cd /a/b\n$ ls\n10 x\n$ cd ../c\n$ ls\n20 y\n$ cd /\n$ cd ..\n$ ls\ndir a";
+        let fs = Shell::run(input, false).unwrap();
+        let a = fs.resolve(ROOT, "a").unwrap();
+        assert_eq!(fs.dirs[a].total_size, 30);
+        assert_eq!(fs.path(fs.resolve(a, "c").unwrap()), "/a/c");
+
+        assert_eq!(
+            Shell::run(input, true).err(),
+            Some(ShellError::NoSuchDir {
+                line: 1,
+                path: "/a/b".to_string()
+            })
+        );
+        assert_eq!(
+            Shell::run("$ ls\ndir a\n$ cd a\n$ cd ../..", true).err(),
+            Some(ShellError::AboveRoot { line: 4 })
+        );
+        assert_eq!(
+            Shell::run("$ pwd", false).err(),
+            Some(ShellError::UnknownCommand {
+                line: 1,
+                text: "$ pwd".to_string()
+            })
+        );
+        assert_eq!(
+            Shell::run("$ cd /\n12 x", false).err(),
+            Some(ShellError::UnexpectedOutput {
+                line: 2,
+                text: "12 x".to_string()
+            })
+        );
+    }
 
-    dbg!(root.part2(must_delete_at_least));
+    #[test]
+    fn test_shell_listings() {
+        // Listing twice doesn't double count.
+        let fs = Shell::run("$ ls\n5 x\ndir a\n$ ls\ndir a\n5 x", true).unwrap();
+        assert_eq!(fs.dirs[ROOT].total_size, 5);
+
+        for (input, line, name) in [
+            ("$ ls\n5 x\n$ ls\n6 x", 3, "x"),
+            ("$ ls\n5 x\n$ ls\n5 x\n1 y", 3, "y"),
+            ("$ ls\n5 x\n$ ls", 3, "x"),
+            ("$ ls\ndir x\n$ ls\n5 x", 3, "x"),
+            ("$ ls\ndir x\n5 x", 1, "x"),
+            ("$ cd a\n$ cd /\n$ ls\n1 b", 3, "a"),
+        ] {
+            assert_eq!(
+                Shell::run(input, false).err(),
+                Some(ShellError::Contradiction {
+                    line,
+                    path: "/".to_string(),
+                    name: name.to_string()
+                }),
+                "{}",
+                input
+            );
+        }
+    }
+
+    const SAMPLE: &str = "\
+$ cd /
+$ ls
+dir a
+14848514 b.txt
+8504156 c.dat
+dir d
+$ cd a
+$ ls
+dir e
+29116 f
+2557 g
+62596 h.lst
+$ cd e
+$ ls
+584 i
+$ cd ..
+$ cd ..
+$ cd d
+$ ls
+4060174 j
+8033020 d.log
+5626152 d.ext
+7214296 k";
+
+    #[test]
+    fn test_reports() {
+        let fs = Shell::run(SAMPLE, true).unwrap();
+        assert_eq!(
+            fs.tree(),
+            "\
+- / (dir, size=48381165)
+  - a (dir, size=94853)
+    - e (dir, size=584)
+      - i (file, size=584)
+    - f (file, size=29116)
+    - g (file, size=2557)
+    - h.lst (file, size=62596)
+  - b.txt (file, size=14848514)
+  - c.dat (file, size=8504156)
+  - d (dir, size=24933642)
+    - d.ext (file, size=5626152)
+    - d.log (file, size=8033020)
+    - j (file, size=4060174)
+    - k (file, size=7214296)
+"
+        );
+        assert_eq!(fs.du(), "47M\t/\n24M\t/d\n93K\t/a\n584\t/a/e\n");
+        assert_eq!(human_size(1024), "1.0K");
+        assert_eq!(human_size(1025), "1.1K");
+        assert_eq!(human_size(10 * 1024 * 1024 - 1), "10M");
+    }
+
+    #[test]
+    fn test_deletion_planner() {
+        let fs = Shell::run(SAMPLE, true).unwrap();
+        let must_free = fs.must_free(70000000, 30000000);
+        assert_eq!(must_free, 8381165);
+        let d = fs.resolve(ROOT, "d").unwrap();
+        assert_eq!(fs.smallest_deletion(must_free), Some(d));
+        assert_eq!(fs.cheapest_deletion_set(must_free), Some(vec![d]));
+        assert_eq!(fs.cheapest_deletion_set(0), Some(vec![]));
+        assert_eq!(fs.cheapest_deletion_set(50_000_000), None);
+
+        // Two small directories can beat one big one, but not when one is inside the other.
+        let fs = Shell::run(
+            "$ ls\ndir a\ndir b\ndir c\n$ cd a\n$ ls\n60 x\n$ cd ../b\n$ ls\n50 y\ndir n\n\
+             $ cd n\n$ ls\n45 z\n$ cd /c\n$ ls\n120 w",
+            true,
+        )
+        .unwrap();
+        let plan = fs.cheapest_deletion_set(100).unwrap();
+        let paths: Vec<String> = plan.iter().map(|id| fs.path(*id)).collect();
+        assert_eq!(paths, vec!["/a", "/b/n"]);
+        assert_eq!(fs.path(fs.smallest_deletion(100).unwrap()), "/c");
+        let plan = fs.cheapest_deletion_set(150).unwrap();
+        let paths: Vec<String> = plan.iter().map(|id| fs.path(*id)).collect();
+        assert_eq!(paths, vec!["/a", "/b"]);
+    }
+
+    #[test]
+    fn test_cheapest_deletion_set_matches_brute_force() {
+        let fs = Shell::run(SAMPLE, true).unwrap();
+        let n = fs.dirs.len();
+        let nested =
+            |a: DirId, b: DirId| fs.path(b).starts_with(&format!("{}/", fs.path(a))) || a == ROOT;
+        for must_free in [1, 584, 585, 94853, 95437, 24933642, 24933643, 48381166] {
+            let brute = (1u32..1 << n)
+                .filter(|mask| {
+                    (0..n).all(|a| {
+                        (0..n).all(|b| {
+                            a == b || mask & (1 << a) == 0 || mask & (1 << b) == 0 || !nested(a, b)
+                        })
+                    })
+                })
+                .map(|mask| {
+                    (0..n)
+                        .filter(|id| mask & (1 << id) != 0)
+                        .map(|id| fs.dirs[id].total_size)
+                        .sum::<usize>()
+                })
+                .filter(|total| *total >= must_free)
+                .min();
+            let plan = fs.cheapest_deletion_set(must_free);
+            let total = plan.map(|p| p.iter().map(|id| fs.dirs[*id].total_size).sum::<usize>());
+            assert_eq!(total, brute, "{}", must_free);
+        }
+    }
+
+    /// Directory paths with their total sizes, and file paths with their sizes.
+    fn summary(fs: &FileSystem) -> Vec<(String, usize)> {
+        let mut out = Vec::new();
+        for (path, dir) in fs.iter_dirs() {
+            out.push((path.clone(), dir.total_size));
+            for (name, size) in dir.files.iter() {
+                out.push((format!("{}/{}", path.trim_end_matches('/'), name), *size));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_transcript() {
+        let fs = Shell::run(SAMPLE, true).unwrap();
+        let options = TranscriptOptions {
+            traversal: Traversal::DepthFirst,
+            revisit_percent: 0,
+            seed: 1,
+        };
+        // Like the sample, except that it lists directories first and goes back up at the end.
+        assert_eq!(
+            fs.transcript(&options),
+            "\
+$ cd /
+$ ls
+dir a
+dir d
+14848514 b.txt
+8504156 c.dat
+$ cd a
+$ ls
+dir e
+29116 f
+2557 g
+62596 h.lst
+$ cd e
+$ ls
+584 i
+$ cd ..
+$ cd ..
+$ cd d
+$ ls
+5626152 d.ext
+8033020 d.log
+4060174 j
+7214296 k
+$ cd ..
+"
+        );
+    }
+
+    #[test]
+    fn test_transcript_round_trip() {
+        let mut rng = lib::Rng(0x9e3779b97f4a7c15);
+        for round in 0..100 {
+            // A random tree.
+            let mut fs = FileSystem::new();
+            for i in 0..rng.below(30) {
+                let parent = rng.below(fs.dirs.len() as u64) as usize;
+                if rng.below(3) == 0 {
+                    fs.mkdir(parent, &format!("d{}", i));
+                } else {
+                    fs.add_file(parent, &format!("f{}.txt", i), rng.below(100_000) as usize);
+                }
+            }
+            for traversal in [Traversal::DepthFirst, Traversal::BreadthFirst] {
+                let options = TranscriptOptions {
+                    traversal,
+                    revisit_percent: 30,
+                    seed: round + 1,
+                };
+                let transcript = fs.transcript(&options);
+                let replayed = Shell::run(&transcript, true)
+                    .unwrap_or_else(|e| panic!("{}\n{}", e, transcript));
+                assert_eq!(summary(&replayed), summary(&fs), "{}", transcript);
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_disk() {
+        let root = std::env::temp_dir().join(format!("day7-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/x"), "hello").unwrap();
+        fs::write(root.join("y"), "abc").unwrap();
+        let disk = FileSystem::from_disk(&root);
+        fs::remove_dir_all(&root).unwrap();
+        let disk = disk.unwrap();
+        assert_eq!(
+            summary(&disk),
+            vec![
+                ("/".to_string(), 8),
+                ("/y".to_string(), 3),
+                ("/a".to_string(), 5),
+                ("/a/b".to_string(), 5),
+                ("/a/b/x".to_string(), 5),
+            ]
+        );
+    }
 }
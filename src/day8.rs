@@ -1,7 +1,20 @@
 use std::iter::repeat_with;
 
+use ansi_term::{Color, Style};
+
 mod lib;
 
+/// Which edge of the forest a tree is looked at from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+const EDGES: [Edge; 4] = [Edge::Top, Edge::Bottom, Edge::Left, Edge::Right];
+
 #[derive(Debug)]
 struct Grid {
     rows: usize,
@@ -28,51 +41,75 @@ impl Grid {
         }
     }
 
-    fn calculate_visible(&mut self) {
-        for row in 0..self.rows {
-            // Left to right.
-            self.visible[row][0] = true;
-            let mut prev_height = self.map[row][0];
-            for col in 1..self.cols {
-                let height = self.map[row][col];
-                if height > prev_height {
-                    self.visible[row][col] = true;
-                }
-                prev_height = prev_height.max(height);
-            }
-            // Right to left.
-            self.visible[row][self.cols - 1] = true;
-            prev_height = self.map[row][self.cols - 1];
-            for col in (0..self.cols - 1).rev() {
+    /// Lines of positions, each starting at `edge` and going into the forest.
+    fn lines_from(&self, edge: Edge) -> Vec<Vec<(usize, usize)>> {
+        let (rows, cols) = (self.rows, self.cols);
+        match edge {
+            Edge::Top => (0..cols)
+                .map(|c| (0..rows).map(|r| (r, c)).collect())
+                .collect(),
+            Edge::Bottom => (0..cols)
+                .map(|c| (0..rows).rev().map(|r| (r, c)).collect())
+                .collect(),
+            Edge::Left => (0..rows)
+                .map(|r| (0..cols).map(|c| (r, c)).collect())
+                .collect(),
+            Edge::Right => (0..rows)
+                .map(|r| (0..cols).rev().map(|c| (r, c)).collect())
+                .collect(),
+        }
+    }
+
+    /// Which trees can be seen from outside the forest at `edge`.
+    fn visibility(&self, edge: Edge) -> Vec<Vec<bool>> {
+        let mut visible = vec![vec![false; self.cols]; self.rows];
+        for line in self.lines_from(edge) {
+            let mut tallest: Option<u8> = None;
+            for (row, col) in line {
                 let height = self.map[row][col];
-                if height > prev_height {
-                    self.visible[row][col] = true;
+                if tallest.is_none_or(|t| height > t) {
+                    visible[row][col] = true;
+                    tallest = Some(height);
                 }
-                prev_height = prev_height.max(height);
             }
         }
-        for col in 0..self.cols {
-            // Top down.
-            self.visible[0][col] = true;
-            let mut prev_height = self.map[0][col];
-            for row in 1..self.rows {
-                let height = self.map[row][col];
-                if height > prev_height {
-                    self.visible[row][col] = true;
+        visible
+    }
+
+    fn calculate_visible(&mut self) {
+        for edge in EDGES {
+            let visible = self.visibility(edge);
+            for (all_row, row) in self.visible.iter_mut().zip(visible) {
+                for (all, v) in all_row.iter_mut().zip(row) {
+                    *all |= v;
                 }
-                prev_height = prev_height.max(height);
             }
-            // Bottom up.
-            self.visible[self.rows - 1][col] = true;
-            let mut prev_height = self.map[self.rows - 1][col];
-            for row in (0..self.rows - 1).rev() {
-                let height = self.map[row][col];
-                if height > prev_height {
-                    self.visible[row][col] = true;
+        }
+    }
+
+    /// Which trees can be seen from the tree at (row, col), looking along its row and column.
+    /// Like from the edges, a tree is visible if everything between it and the viewpoint is
+    /// shorter.
+    fn visibility_from(&self, row: usize, col: usize) -> Vec<Vec<bool>> {
+        let mut visible = vec![vec![false; self.cols]; self.rows];
+        visible[row][col] = true;
+        let rays: [Vec<(usize, usize)>; 4] = [
+            (0..row).rev().map(|r| (r, col)).collect(),
+            (row + 1..self.rows).map(|r| (r, col)).collect(),
+            (0..col).rev().map(|c| (row, c)).collect(),
+            (col + 1..self.cols).map(|c| (row, c)).collect(),
+        ];
+        for ray in rays {
+            let mut tallest: Option<u8> = None;
+            for (r, c) in ray {
+                let height = self.map[r][c];
+                if tallest.is_none_or(|t| height > t) {
+                    visible[r][c] = true;
                 }
-                prev_height = prev_height.max(height);
+                tallest = tallest.max(Some(height));
             }
         }
+        visible
     }
 
     fn count_visible(&self) -> usize {
@@ -82,40 +119,129 @@ impl Grid {
             .sum()
     }
 
-    fn calculate_scenic_score(&self, row: usize, col: usize) -> usize {
-        let mut scenic_score = 1;
-        for (d_row, d_col) in [(-1isize, 0isize), (1, 0), (0, -1), (0, 1)] {
-            let mut row_ = row as isize;
-            let mut col_ = col as isize;
-            let house_height = self.map[row][col];
-            let mut distance = 0;
-            while row_ != 0
-                && row_ != (self.rows - 1) as isize
-                && col_ != 0
-                && col_ != (self.cols - 1) as isize
-            {
-                distance += 1;
-                row_ += d_row;
-                col_ += d_col;
-                let tree_height = self.map[row_ as usize][col_ as usize];
-                if tree_height >= house_height {
-                    break;
-                }
+    /// Scenic score of every tree, in O(rows * cols).
+    fn scenic_scores(&self) -> Vec<Vec<usize>> {
+        let mut scores = vec![vec![1; self.cols]; self.rows];
+        for (line, scores_row) in self.map.iter().zip(scores.iter_mut()) {
+            let left = viewing_distances(line);
+            let right = viewing_distances_reversed(line);
+            for (score, (l, r)) in scores_row.iter_mut().zip(left.iter().zip(&right)) {
+                *score *= l * r;
             }
-            scenic_score *= distance;
         }
-        scenic_score
+        for col in 0..self.cols {
+            let line: Vec<u8> = self.map.iter().map(|r| r[col]).collect();
+            let up = viewing_distances(&line);
+            let down = viewing_distances_reversed(&line);
+            for (row, scores_row) in scores.iter_mut().enumerate() {
+                scores_row[col] *= up[row] * down[row];
+            }
+        }
+        scores
     }
 
     fn find_highest_scenic_score(&self) -> usize {
-        let mut max_score = 0usize;
+        self.scenic_scores()
+            .iter()
+            .flat_map(|row| row.iter())
+            .max()
+            .cloned()
+            .unwrap_or(0)
+    }
+}
+
+impl Grid {
+    /// The `k` best scenic scores as (row, col, score), best first. Ties go to the top left.
+    fn top_scenic(&self, k: usize) -> Vec<(usize, usize, usize)> {
+        let mut all: Vec<(usize, usize, usize)> = self
+            .scenic_scores()
+            .into_iter()
+            .enumerate()
+            .flat_map(|(row, scores)| {
+                scores
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(col, score)| (row, col, score))
+            })
+            .collect();
+        all.sort_by_key(|(row, col, score)| (std::cmp::Reverse(*score), *row, *col));
+        all.truncate(k);
+        all
+    }
+
+    /// Draws the forest with every tree's cell colored by `shade`, which maps a position to a
+    /// 0..=9 level and whether to highlight it.
+    fn heatmap(&self, shade: impl Fn(usize, usize) -> (u8, bool)) -> String {
+        let mut out = String::new();
         for row in 0..self.rows {
             for col in 0..self.cols {
-                max_score = max_score.max(self.calculate_scenic_score(row, col));
+                let (level, highlight) = shade(row, col);
+                // The 256 color palette has a 24 step grayscale ramp starting at 232.
+                let background = Color::Fixed(232 + level.min(9) * 2 + 4);
+                let style = if highlight {
+                    Color::Black.on(Color::Green)
+                } else {
+                    Style::new().on(background)
+                };
+                out += &style
+                    .paint(((b'0' + self.map[row][col]) as char).to_string())
+                    .to_string();
             }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn height_heatmap(&self) -> String {
+        self.heatmap(|row, col| (self.map[row][col], false))
+    }
+
+    /// Heights, with the trees marked in `visible` standing out.
+    fn visibility_heatmap(&self, visible: &[Vec<bool>]) -> String {
+        self.heatmap(|row, col| (self.map[row][col], visible[row][col]))
+    }
+
+    /// Scenic scores on a log scale, with the best spot highlighted.
+    fn scenic_heatmap(&self) -> String {
+        let scores = self.scenic_scores();
+        let best = self.find_highest_scenic_score();
+        let log = |score: usize| ((score + 1) as f64).ln();
+        self.heatmap(|row, col| {
+            let score = scores[row][col];
+            let level = if best == 0 {
+                0
+            } else {
+                (9.0 * log(score) / log(best)).round() as u8
+            };
+            (level, score == best && best > 0)
+        })
+    }
+}
+
+/// For every tree in `line`, how many trees it can see looking towards the start of the line:
+/// up to and including the first one at least as tall, or up to the edge.
+///
+/// Keeps a stack of trees that could still block the view of a later tree. A tree hides every
+/// shorter tree before it, so those get popped, and what's left on top blocks the view.
+fn viewing_distances(line: &[u8]) -> Vec<usize> {
+    let mut stack: Vec<usize> = Vec::new();
+    let mut out = Vec::with_capacity(line.len());
+    for (i, height) in line.iter().enumerate() {
+        while stack.last().is_some_and(|top| line[*top] < *height) {
+            stack.pop();
         }
-        max_score
+        out.push(i - stack.last().cloned().unwrap_or(0));
+        stack.push(i);
     }
+    out
+}
+
+/// Same as `viewing_distances`, but looking towards the end of the line.
+fn viewing_distances_reversed(line: &[u8]) -> Vec<usize> {
+    let reversed: Vec<u8> = line.iter().rev().cloned().collect();
+    let mut out = viewing_distances(&reversed);
+    out.reverse();
+    out
 }
 
 fn main() {
@@ -125,4 +251,147 @@ fn main() {
     // dbg!(&grid);
     dbg!(grid.count_visible());
     dbg!(grid.find_highest_scenic_score());
+
+    for arg in std::env::args().skip(2) {
+        if arg == "heatmap" {
+            println!("Heights:\n{}", grid.height_heatmap());
+            println!("Visible:\n{}", grid.visibility_heatmap(&grid.visible));
+            println!("Scenic scores:\n{}", grid.scenic_heatmap());
+            for edge in EDGES {
+                let count = grid
+                    .visibility(edge)
+                    .iter()
+                    .flatten()
+                    .filter(|v| **v)
+                    .count();
+                println!("Visible from {:?}: {}", edge, count);
+            }
+        } else if let Some(k) = arg.strip_prefix("top=") {
+            dbg!(grid.top_scenic(k.parse().unwrap()));
+        } else if let Some(pos) = arg.strip_prefix("from=") {
+            let (row, col) = pos.split_once(",").unwrap();
+            let visible = grid.visibility_from(row.parse().unwrap(), col.parse().unwrap());
+            println!("{}", grid.visibility_heatmap(&visible));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "30373\n25512\n65332\n33549\n35390";
+
+    #[test]
+    fn test_visibility() {
+        let mut grid = Grid::parse(SAMPLE);
+        grid.calculate_visible();
+        assert_eq!(grid.count_visible(), 21);
+        let from_left = grid.visibility(Edge::Left);
+        assert_eq!(from_left[1], vec![true, true, false, false, false]);
+        let from_top = grid.visibility(Edge::Top);
+        assert_eq!(
+            from_top.iter().map(|row| row[3]).collect::<Vec<_>>(),
+            vec![true, false, false, false, true]
+        );
+
+        // From the 5 in the middle of row 1 (25512): both 5s are in the way of each other.
+        let visible = grid.visibility_from(1, 2);
+        assert_eq!(visible[1], vec![false, true, true, true, true]);
+        let column: Vec<bool> = visible.iter().map(|row| row[2]).collect();
+        assert_eq!(column, vec![true, true, true, true, false]);
+    }
+
+    #[test]
+    fn test_top_scenic() {
+        let grid = Grid::parse(SAMPLE);
+        assert_eq!(grid.top_scenic(2), vec![(3, 2, 8), (2, 1, 6)]);
+    }
+
+    #[test]
+    fn test_scenic_heatmap() {
+        let grid = Grid::parse(SAMPLE);
+        // Every cell is painted on its own, so each ends with a reset.
+        let cells: Vec<Vec<String>> = grid
+            .scenic_heatmap()
+            .lines()
+            .map(|line| {
+                line.split_inclusive("\x1b[0m")
+                    .map(str::to_string)
+                    .collect()
+            })
+            .collect();
+        assert!(cells.iter().all(|row| row.len() == 5));
+        let shaded = |level: u8, height: &str| {
+            Style::new()
+                .on(Color::Fixed(232 + level * 2 + 4))
+                .paint(height)
+                .to_string()
+        };
+
+        // Only the best spot stands out: the 5 in row 3 with a score of 8.
+        let highlight = Color::Black.on(Color::Green).paint("5").to_string();
+        let highlighted: Vec<(usize, usize)> = (0..5)
+            .flat_map(|row| (0..5).map(move |col| (row, col)))
+            .filter(|&(row, col)| cells[row][col] == highlight)
+            .collect();
+        assert_eq!(highlighted, vec![(3, 2)]);
+        // Trees on the edge see nothing one way, so they get the darkest shade.
+        assert_eq!(cells[0][0], shaded(0, "3"));
+        // A score of 4 on a log scale up to 8: 9 * ln 5 / ln 9 rounds to 7.
+        assert_eq!(cells[1][2], shaded(7, "5"));
+    }
+
+    /// Walks outwards from the tree in all four directions.
+    fn brute_force_scenic_score(grid: &Grid, row: usize, col: usize) -> usize {
+        let house_height = grid.map[row][col];
+        let mut scenic_score = 1;
+        for (d_row, d_col) in [(-1isize, 0isize), (1, 0), (0, -1), (0, 1)] {
+            let (mut row_, mut col_) = (row as isize, col as isize);
+            let mut distance = 0;
+            loop {
+                row_ += d_row;
+                col_ += d_col;
+                if row_ < 0 || row_ >= grid.rows as isize || col_ < 0 || col_ >= grid.cols as isize
+                {
+                    break;
+                }
+                distance += 1;
+                if grid.map[row_ as usize][col_ as usize] >= house_height {
+                    break;
+                }
+            }
+            scenic_score *= distance;
+        }
+        scenic_score
+    }
+
+    #[test]
+    fn test_scenic_scores_match_brute_force() {
+        let sample = Grid::parse(SAMPLE);
+        assert_eq!(sample.scenic_scores()[3][2], 8);
+        assert_eq!(sample.find_highest_scenic_score(), 8);
+
+        let mut rng = lib::Rng(88172645463325252);
+        for _ in 0..50 {
+            let input: String = (0..7)
+                .map(|_| {
+                    (0..9)
+                        .map(|_| {
+                            // Few distinct heights, so that there are lots of ties.
+                            (b'0' + rng.below(4) as u8) as char
+                        })
+                        .collect::<String>()
+                        + "\n"
+                })
+                .collect();
+            let grid = Grid::parse(&input);
+            let scores = grid.scenic_scores();
+            for (row, scores_row) in scores.iter().enumerate() {
+                for (col, score) in scores_row.iter().enumerate() {
+                    assert_eq!(*score, brute_force_scenic_score(&grid, row, col));
+                }
+            }
+        }
+    }
 }
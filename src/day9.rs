@@ -1,49 +1,236 @@
-use std::{collections::HashSet, iter::repeat};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::{repeat, repeat_with},
+};
 
 mod lib;
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
 struct Pos(isize, isize);
 
+/// The four directions a straight run of cells can go in, up to sign. A line in direction `i`
+/// is the set of cells where `a * y + b * x` equals its key, for `LINE_KEYS[i] == (a, b)`.
+const DIRECTIONS: [(isize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+const LINE_KEYS: [(isize, isize); 4] = [(1, 0), (0, 1), (1, -1), (1, 1)];
+
+/// Which line in direction `dir` a cell is on, and how far along that line it is.
+fn line_coords(dir: usize, pos: &Pos) -> (isize, isize) {
+    let (a, b) = LINE_KEYS[dir];
+    let along = if dir == 0 { pos.1 } else { pos.0 };
+    (a * pos.0 + b * pos.1, along)
+}
+
+/// The cell where line `key_a` in direction `a` crosses line `key_b` in direction `b`, if
+/// they cross on a cell.
+fn crossing(a: usize, key_a: isize, b: usize, key_b: isize) -> Option<Pos> {
+    let ((a1, b1), (a2, b2)) = (LINE_KEYS[a], LINE_KEYS[b]);
+    let det = a1 * b2 - b1 * a2;
+    let (y, x) = (key_a * b2 - b1 * key_b, a1 * key_b - key_a * a2);
+    if det == 0 || y % det != 0 || x % det != 0 {
+        return None;
+    }
+    Some(Pos(y / det, x / det))
+}
+
+/// Cells `start + k * step` for `k` in `0..len`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    start: Pos,
+    step: (isize, isize),
+    len: isize,
+}
+
+impl Segment {
+    fn end(&self) -> Pos {
+        Pos(
+            self.start.0 + (self.len - 1) * self.step.0,
+            self.start.1 + (self.len - 1) * self.step.1,
+        )
+    }
+
+    fn contains(&self, pos: &Pos) -> bool {
+        let (dy, dx) = (pos.0 - self.start.0, pos.1 - self.start.1);
+        let k = if self.step.0 != 0 {
+            dy / self.step.0
+        } else {
+            dx / self.step.1
+        };
+        (0..self.len).contains(&k) && dy == k * self.step.0 && dx == k * self.step.1
+    }
+
+    /// (direction, line key, first and last position along the line).
+    fn line(&self) -> (usize, isize, isize, isize) {
+        let dir = DIRECTIONS
+            .iter()
+            .position(|(dy, dx)| self.step == (*dy, *dx) || self.step == (-dy, -dx))
+            .unwrap();
+        let (key, start) = line_coords(dir, &self.start);
+        let (_, end) = line_coords(dir, &self.end());
+        (dir, key, start.min(end), start.max(end))
+    }
+}
+
+/// Everywhere a knot has been, as straight runs of cells. Runs can overlap.
+#[derive(Debug)]
+struct Trail {
+    segments: Vec<Segment>,
+}
+
+impl Trail {
+    fn new(start: Pos) -> Trail {
+        Trail {
+            segments: vec![Segment {
+                start,
+                step: (0, 1),
+                len: 1,
+            }],
+        }
+    }
+
+    /// Adds `len` cells from `start` on in direction `step`, extending the last run if this
+    /// carries straight on from it.
+    fn add(&mut self, start: Pos, step: (isize, isize), len: isize) {
+        let last = self.segments.last_mut().unwrap();
+        let end = last.end();
+        let continues = start == Pos(end.0 + step.0, end.1 + step.1);
+        if continues && (last.len == 1 || last.step == step) {
+            last.step = step;
+            last.len += len;
+        } else {
+            self.segments.push(Segment { start, step, len });
+        }
+    }
+
+    /// Records that the knot is now at `pos`.
+    fn push(&mut self, pos: &Pos) {
+        let end = self.segments.last().unwrap().end();
+        let step = (pos.0 - end.0, pos.1 - end.1);
+        match step {
+            (0, 0) => {}
+            (-1..=1, -1..=1) => self.add(pos.clone(), step, 1),
+            _ => self.add(pos.clone(), (0, 1), 1),
+        }
+    }
+
+    fn contains(&self, pos: &Pos) -> bool {
+        self.segments.iter().any(|segment| segment.contains(pos))
+    }
+
+    /// Number of distinct cells on the trail.
+    ///
+    /// Runs on the same line are merged into disjoint intervals and measured. Lines in
+    /// different directions only meet in single cells, which are then counted once.
+    fn count(&self) -> usize {
+        let mut lines: [HashMap<isize, Vec<(isize, isize)>>; 4] = Default::default();
+        for segment in self.segments.iter() {
+            let (dir, key, lo, hi) = segment.line();
+            lines[dir].entry(key).or_default().push((lo, hi));
+        }
+        for intervals in lines.iter_mut().flat_map(|l| l.values_mut()) {
+            intervals.sort();
+            let mut merged: Vec<(isize, isize)> = Vec::new();
+            for (lo, hi) in intervals.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if lo <= last.1 + 1 => last.1 = last.1.max(hi),
+                    _ => merged.push((lo, hi)),
+                }
+            }
+            *intervals = merged;
+        }
+        let covers = |dir: usize, pos: &Pos| {
+            let (key, along) = line_coords(dir, pos);
+            lines[dir].get(&key).is_some_and(|intervals| {
+                let i = intervals.partition_point(|(_, hi)| *hi < along);
+                i < intervals.len() && intervals[i].0 <= along
+            })
+        };
+
+        let mut total: usize = lines
+            .iter()
+            .flat_map(|l| l.values().flatten())
+            .map(|(lo, hi)| (hi - lo + 1) as usize)
+            .sum();
+        let mut crossings = HashSet::new();
+        for a in 0..4 {
+            for b in a + 1..4 {
+                for key_a in lines[a].keys() {
+                    for key_b in lines[b].keys() {
+                        if let Some(pos) = crossing(a, *key_a, b, *key_b) {
+                            if covers(a, &pos) && covers(b, &pos) {
+                                crossings.insert(pos);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for pos in crossings {
+            total -= (0..4).filter(|dir| covers(*dir, &pos)).count() - 1;
+        }
+        total
+    }
+}
+
 #[derive(Debug)]
 struct State {
     // Part 1: [0] is Head, [1] is tail.
     // Part 2: [0] is Head, [1] is 1, etc.
     rope: Vec<Pos>,
-    tail_visited: HashSet<Pos>,
+    /// Everywhere each knot has been.
+    trails: Vec<Trail>,
+    /// How far (in king moves) a knot can be from the one before it without being pulled.
+    max_slack: isize,
 }
 
 impl State {
-    fn new(segments: usize) -> State {
+    fn new(segments: usize, max_slack: isize) -> State {
         State {
             rope: repeat(Pos(0, 0)).take(segments).collect(),
-            tail_visited: HashSet::default(),
+            trails: repeat_with(|| Trail::new(Pos(0, 0)))
+                .take(segments)
+                .collect(),
+            max_slack,
         }
     }
 
     fn parse_and_do_move(&mut self, line: &str) {
         let (dir, count) = line.split_once(" ").unwrap();
-        self.do_move(dir.chars().next().unwrap(), count.parse().unwrap());
+        self.do_move(dir, count.parse().unwrap());
     }
 
-    fn do_move(&mut self, dir: char, count: isize) {
-        for _ in 0..count {
-            self.move_head(dir);
+    fn do_move(&mut self, dir: &str, count: isize) {
+        let (dy, dx) = parse_dir(dir);
+        let mut remaining = count;
+        while remaining > 0 {
+            let before = self.rope.clone();
+            self.move_head(dy, dx);
             for i in 1..self.rope.len() {
                 self.move_tail(i);
             }
-            self.tail_visited.insert(self.rope.last().unwrap().clone());
+            for (knot, trail) in self.rope.iter().zip(self.trails.iter_mut()) {
+                trail.push(knot);
+            }
+            remaining -= 1;
+
+            // Once every knot moves the same way as the head, the rope is stretched and will
+            // keep moving like that, so skip ahead to the end of the move.
+            let lockstep = self
+                .rope
+                .iter()
+                .zip(before.iter())
+                .all(|(after, before)| *after == Pos(before.0 + dy, before.1 + dx));
+            if lockstep && remaining > 0 {
+                for (knot, trail) in self.rope.iter_mut().zip(self.trails.iter_mut()) {
+                    trail.add(Pos(knot.0 + dy, knot.1 + dx), (dy, dx), remaining);
+                    knot.0 += dy * remaining;
+                    knot.1 += dx * remaining;
+                }
+                remaining = 0;
+            }
         }
     }
 
-    fn move_head(&mut self, dir: char) {
-        let (dy, dx): (isize, isize) = match dir {
-            'R' => (0, 1),
-            'L' => (0, -1),
-            'U' => (-1, 0),
-            'D' => (1, 0),
-            _ => panic!("invalid dir"),
-        };
+    fn move_head(&mut self, dy: isize, dx: isize) {
         self.rope[0].0 += dy;
         self.rope[0].1 += dx;
     }
@@ -51,23 +238,203 @@ impl State {
     fn move_tail(&mut self, i: usize) {
         let dy = self.rope[i - 1].0 - self.rope[i].0;
         let dx = self.rope[i - 1].1 - self.rope[i].1;
-        if dy.abs() > 1 || dx.abs() > 1 {
+        if dy.abs() > self.max_slack || dx.abs() > self.max_slack {
             self.rope[i].0 += dy.signum();
             self.rope[i].1 += dx.signum();
         }
     }
 
     fn run(input: &str, segments: usize) -> usize {
-        let mut state = Self::new(segments);
+        let mut state = Self::new(segments, 1);
         input.lines().for_each(|line| state.parse_and_do_move(line));
-        state.tail_visited.len()
+        state.trails.last().unwrap().count()
+    }
+
+    /// Draws the rope over the trail of knot `trail_knot`, in the puzzle's style: `H` for the
+    /// head, then the knot numbers, `s` for the start and `#` for the trail.
+    fn draw(&self, trail_knot: usize) -> String {
+        let ends = self
+            .trails
+            .iter()
+            .flat_map(|trail| trail.segments.iter())
+            .flat_map(|segment| [segment.start.clone(), segment.end()]);
+        let all = ends.chain(self.rope.iter().cloned());
+        let (min_y, max_y) = all
+            .clone()
+            .fold((0, 0), |(lo, hi), p| (p.0.min(lo), p.0.max(hi)));
+        let (min_x, max_x) = all.fold((0, 0), |(lo, hi), p| (p.1.min(lo), p.1.max(hi)));
+        let mut out = String::new();
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                let pos = Pos(y, x);
+                let c = match self.rope.iter().position(|knot| *knot == pos) {
+                    Some(0) => 'H',
+                    Some(i) => knot_label(i),
+                    None if pos == Pos(0, 0) => 's',
+                    None if self.trails[trail_knot].contains(&pos) => '#',
+                    None => '.',
+                };
+                out.push(c);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Single character name for knot `i` > 0: 1..9, then a..z, then *.
+fn knot_label(i: usize) -> char {
+    match i {
+        1..=9 => (b'0' + i as u8) as char,
+        10..=35 => (b'a' + (i - 10) as u8) as char,
+        _ => '*',
+    }
+}
+
+/// Turns a direction like "R" or "UL" into a (dy, dx) step.
+fn parse_dir(dir: &str) -> (isize, isize) {
+    let (mut dy, mut dx) = (0isize, 0isize);
+    for c in dir.chars() {
+        let (ddy, ddx) = match c {
+            'R' => (0, 1),
+            'L' => (0, -1),
+            'U' => (-1, 0),
+            'D' => (1, 0),
+            _ => panic!("invalid dir"),
+        };
+        // No "UU" or "LR".
+        if (ddy != 0 && dy != 0) || (ddx != 0 && dx != 0) {
+            panic!("invalid dir");
+        }
+        dy += ddy;
+        dx += ddx;
+    }
+    if (dy, dx) == (0, 0) {
+        panic!("invalid dir");
     }
+    (dy, dx)
 }
 
 fn main() {
     let input = lib::read_input();
-    // Part 1:
-    dbg!(State::run(&input, 2));
-    // Part 2:
-    dbg!(State::run(&input, 10));
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    if args.is_empty() {
+        // Part 1:
+        dbg!(State::run(&input, 2));
+        // Part 2:
+        dbg!(State::run(&input, 10));
+        return;
+    }
+
+    let option = |name: &str| {
+        args.iter()
+            .find_map(|arg| arg.strip_prefix(&format!("{}=", name)))
+            .map(|value| value.parse::<usize>().unwrap())
+    };
+    let knots = option("knots").unwrap_or(10);
+    let mut state = State::new(knots, option("slack").unwrap_or(1) as isize);
+    let draw = option("draw");
+    if let Some(knot) = draw.filter(|knot| *knot >= knots) {
+        println!("can't draw knot {}: the rope has {} knots", knot, knots);
+        return;
+    }
+    for line in input.lines() {
+        state.parse_and_do_move(line);
+        if let Some(knot) = draw {
+            println!("== {} ==\n\n{}", line, state.draw(knot));
+        }
+    }
+    for (i, trail) in state.trails.iter().enumerate() {
+        println!("knot {} visited {} positions", i, trail.count());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "R 4\nU 4\nL 3\nD 1\nR 4\nD 1\nL 5\nR 2";
+
+    #[test]
+    fn test_run() {
+        assert_eq!(State::run(SAMPLE, 2), 13);
+        assert_eq!(State::run(SAMPLE, 10), 1);
+    }
+
+    #[test]
+    fn test_diagonal_and_slack() {
+        assert_eq!(parse_dir("UR"), (-1, 1));
+        assert_eq!(parse_dir("LD"), (1, -1));
+
+        let mut state = State::new(3, 1);
+        state.do_move("DR", 3);
+        assert_eq!(state.rope, vec![Pos(3, 3), Pos(2, 2), Pos(1, 1)]);
+        assert_eq!(state.trails[1].count(), 3);
+
+        // With more slack, knots only start moving once the rope before them is stretched.
+        let mut state = State::new(3, 2);
+        state.do_move("R", 5);
+        assert_eq!(state.rope, vec![Pos(0, 5), Pos(0, 3), Pos(0, 1)]);
+        assert_eq!(state.trails[2].count(), 2);
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut state = State::new(3, 1);
+        state.do_move("R", 4);
+        state.do_move("U", 1);
+        assert_eq!(state.draw(2), "....H\ns#21.\n");
+    }
+
+    /// Steps one cell at a time and remembers every cell.
+    fn brute_force(input: &str, segments: usize, max_slack: isize) -> Vec<usize> {
+        let mut rope = vec![Pos(0, 0); segments];
+        let mut visited = vec![HashSet::from([Pos(0, 0)]); segments];
+        for line in input.lines() {
+            let (dir, count) = line.split_once(" ").unwrap();
+            let (dy, dx) = parse_dir(dir);
+            for _ in 0..count.parse::<usize>().unwrap() {
+                rope[0] = Pos(rope[0].0 + dy, rope[0].1 + dx);
+                for i in 1..segments {
+                    let (dy, dx) = (rope[i - 1].0 - rope[i].0, rope[i - 1].1 - rope[i].1);
+                    if dy.abs() > max_slack || dx.abs() > max_slack {
+                        rope[i] = Pos(rope[i].0 + dy.signum(), rope[i].1 + dx.signum());
+                    }
+                }
+                for (knot, visited) in rope.iter().zip(visited.iter_mut()) {
+                    visited.insert(knot.clone());
+                }
+            }
+        }
+        visited.iter().map(|v| v.len()).collect()
+    }
+
+    #[test]
+    fn test_fast_forward_matches_brute_force() {
+        let dirs = ["R", "L", "U", "D", "UR", "UL", "DR", "DL"];
+        let mut rng = lib::Rng(0x853c49e6748fea9b);
+        let mut random = |n: usize| rng.below(n as u64) as usize;
+        for _ in 0..200 {
+            let input: Vec<String> = (0..1 + random(30))
+                .map(|_| format!("{} {}", dirs[random(8)], 1 + random(25)))
+                .collect();
+            let input = input.join("\n");
+            let (knots, slack) = (1 + random(10), 1 + random(3) as isize);
+            let mut state = State::new(knots, slack);
+            input.lines().for_each(|line| state.parse_and_do_move(line));
+            let counts: Vec<usize> = state.trails.iter().map(|t| t.count()).collect();
+            assert_eq!(counts, brute_force(&input, knots, slack), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_huge_moves() {
+        let mut state = State::new(10, 1);
+        state.parse_and_do_move("R 1000000000");
+        state.parse_and_do_move("UL 1000000000");
+        assert_eq!(state.rope[9], Pos(-999_999_991, 0));
+        // The tail goes right, then diagonally back up to its starting column.
+        assert_eq!(state.trails[9].count(), 999_999_992 + 999_999_991);
+        assert!(state.trails.iter().all(|t| t.segments.len() < 30));
+    }
 }
@@ -23,3 +23,18 @@ impl Between for isize {
         self >= from && self < to
     }
 }
+
+/// xorshift64, which is plenty for shuffling test inputs. The seed must not be 0. Most days only
+/// use it in tests, hence the `allow`s.
+#[allow(dead_code)]
+pub struct Rng(pub u64);
+
+#[allow(dead_code)]
+impl Rng {
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}